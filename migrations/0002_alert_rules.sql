@@ -0,0 +1,15 @@
+CREATE TABLE alert_rules (
+  id            SERIAL PRIMARY KEY,
+  device_id     INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  user_id       INT NOT NULL REFERENCES users(id) ON DELETE CASCADE, -- владелец правила, его и уведомляем
+  name          TEXT NOT NULL DEFAULT '',
+  path          TEXT NOT NULL,              -- путь в payload: "temp" или "sensors.0.t"
+  op            TEXT NOT NULL,              -- > >= < <= == !=
+  threshold     DOUBLE PRECISION NOT NULL,
+  hysteresis    DOUBLE PRECISION NOT NULL DEFAULT 0,
+  cooldown_sec  INT NOT NULL DEFAULT 0,     -- не чаще чем раз в столько секунд
+  firing        BOOLEAN NOT NULL DEFAULT false,
+  last_fired    TIMESTAMPTZ,
+  time_reg      TIMESTAMPTZ DEFAULT now()
+);
+CREATE INDEX alert_rules_device_idx ON alert_rules(device_id);
@@ -0,0 +1,17 @@
+-- кто хочет знать, что устройство пропало / вернулось
+CREATE TABLE device_watch (
+  device_id       INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  user_id         INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  offline_minutes INT NOT NULL DEFAULT 5,
+  time_reg        TIMESTAMPTZ DEFAULT now(),
+  PRIMARY KEY (device_id, user_id)
+);
+
+CREATE TABLE device_events (
+  id          BIGSERIAL PRIMARY KEY,
+  device_id   INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  kind        TEXT NOT NULL,          -- offline / online
+  time        TIMESTAMPTZ NOT NULL DEFAULT now(),
+  details     JSONB
+);
+CREATE INDEX device_events_device_time_idx ON device_events(device_id, time DESC);
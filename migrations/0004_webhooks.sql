@@ -0,0 +1,23 @@
+CREATE TABLE webhooks (
+  id          SERIAL PRIMARY KEY,
+  device_id   INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  user_id     INT NOT NULL REFERENCES users(id) ON DELETE CASCADE, -- кто зарегистрировал
+  url         TEXT NOT NULL,
+  secret      TEXT NOT NULL,          -- ключ HMAC-SHA256 для X-Aguardia-Signature
+  active      BOOLEAN NOT NULL DEFAULT true,
+  time_reg    TIMESTAMPTZ DEFAULT now()
+);
+CREATE INDEX webhooks_device_idx ON webhooks(device_id);
+
+CREATE TABLE webhook_deliveries (
+  id            BIGSERIAL PRIMARY KEY,
+  webhook_id    INT NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+  event         TEXT NOT NULL,        -- data / device_online / device_offline / alert / alert_clear
+  payload       JSONB NOT NULL,
+  attempts      INT NOT NULL,
+  ok            BOOLEAN NOT NULL,
+  status_code   INT,                  -- последний HTTP код
+  error         TEXT,
+  time          TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX webhook_deliveries_hook_time_idx ON webhook_deliveries(webhook_id, time DESC);
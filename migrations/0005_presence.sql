@@ -0,0 +1,8 @@
+-- когда устройство/пользователь был на связи (отдельно от users, чтобы не трогать time_upd)
+CREATE TABLE presence (
+  user_id          INT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  last_connect     TIMESTAMPTZ,
+  last_disconnect  TIMESTAMPTZ,
+  last_seen        TIMESTAMPTZ,
+  last_ip          TEXT
+);
@@ -0,0 +1,2 @@
+-- sha256(hex) от токена MQTT, сам токен отдается один раз в set_mqtt_token
+ALTER TABLE users ADD COLUMN mqtt_token TEXT;
@@ -0,0 +1,15 @@
+-- несколько серверов за балансировщиком: кто где подключен
+CREATE TABLE cluster_nodes (
+  node_id     TEXT PRIMARY KEY,
+  last_beat   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE cluster_sessions (
+  user_id       INT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  node_id       TEXT NOT NULL,
+  public_x      BYTEA NOT NULL,
+  public_ed     BYTEA NOT NULL,
+  connected_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX cluster_sessions_node ON cluster_sessions(node_id);
@@ -0,0 +1,13 @@
+-- временные баны за флуд (public_ed и/или IP)
+CREATE TABLE bans (
+  id          SERIAL PRIMARY KEY,
+  user_id     INT REFERENCES users(id) ON DELETE SET NULL,
+  public_ed   BYTEA,
+  ip          TEXT,
+  reason      TEXT NOT NULL,
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT now(),
+  until       TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX bans_public_ed ON bans(public_ed, until);
+CREATE INDEX bans_ip ON bans(ip, until);
@@ -0,0 +1,25 @@
+-- журнал безопасности: только INSERT (и DELETE старого по retention), UPDATE запрещен
+-- actor/target без FK: история удаленных пользователей и устройств должна остаться
+CREATE TABLE audit_log (
+  id        BIGSERIAL PRIMARY KEY,
+  time      TIMESTAMPTZ NOT NULL DEFAULT now(),
+  actor     INT,
+  target    INT,
+  action    TEXT NOT NULL,
+  ip        TEXT,
+  result    TEXT NOT NULL,          -- ok / denied / failed
+  details   JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX audit_log_time ON audit_log(time);
+CREATE INDEX audit_log_actor ON audit_log(actor, time);
+CREATE INDEX audit_log_target ON audit_log(target, time);
+
+CREATE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_log is append-only';
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+  FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
@@ -0,0 +1,20 @@
+-- роли и права; config admins остаются bootstrap-суперпользователями (все права, без записи здесь)
+-- permissions: read_any, write_any, provision, users_read, users_write, audit_read, config, roles; '*' = все
+CREATE TABLE roles (
+  name         TEXT PRIMARY KEY,
+  permissions  TEXT[] NOT NULL DEFAULT '{}',
+  info         TEXT NOT NULL DEFAULT ''
+);
+
+INSERT INTO roles (name, permissions, info) VALUES
+  ('admin',       '{*}',                              'all permissions'),
+  ('support',     '{read_any,users_read,audit_read}', 'read-only: devices, users, sessions, audit log'),
+  ('provisioner', '{provision}',                      'create devices for other owners, issue MQTT tokens');
+
+CREATE TABLE user_roles (
+  user_id     INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role        TEXT NOT NULL REFERENCES roles(name) ON DELETE CASCADE ON UPDATE CASCADE,
+  granted_by  INT,
+  time        TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, role)
+);
@@ -0,0 +1,8 @@
+-- блокировка без удаления данных: suspended - временно (status_until) или до unsuspend, blocked - украденное устройство / абьюз
+ALTER TABLE users
+  ADD COLUMN status        TEXT NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'suspended', 'blocked')),
+  ADD COLUMN status_reason TEXT,
+  ADD COLUMN status_until  TIMESTAMPTZ,
+  ADD COLUMN status_by     INT;
+
+CREATE INDEX users_status ON users(status) WHERE status <> 'active';
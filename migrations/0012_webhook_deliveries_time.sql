@@ -0,0 +1,2 @@
+-- retention: DELETE ... WHERE time < now() - webhook_log_days
+CREATE INDEX webhook_deliveries_time_idx ON webhook_deliveries(time);
@@ -0,0 +1,21 @@
+-- audit_log: DELETE только старше retention, TRUNCATE запрещен
+-- retention передает сервер в транзакции удаления: set_config('aguardia.audit_retention_days', N, true)
+-- без настройки (ручной DELETE, retention = 0) удалить нельзя ничего
+CREATE FUNCTION audit_log_delete_expired() RETURNS trigger AS $$
+DECLARE
+  days INT := NULLIF(current_setting('aguardia.audit_retention_days', true), '')::INT;
+BEGIN
+  IF days IS NULL OR days <= 0 OR OLD.time >= now() - make_interval(days => days) THEN
+    RAISE EXCEPTION 'audit_log: only records older than retention can be deleted';
+  END IF;
+  RETURN OLD;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+  FOR EACH ROW EXECUTE FUNCTION audit_log_delete_expired();
+
+CREATE TRIGGER audit_log_no_truncate BEFORE TRUNCATE ON audit_log
+  FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+
+REVOKE TRUNCATE ON audit_log FROM PUBLIC;
@@ -0,0 +1,9 @@
+-- на каких узлах у устройства есть подписчики телеметрии: без них broadcast_data не шлет NOTIFY
+-- чистится как cluster_sessions: при старте / уходе узла и по таймауту cluster_nodes
+CREATE TABLE cluster_subscriptions (
+  device_id  INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  node_id    TEXT NOT NULL,
+  PRIMARY KEY (device_id, node_id)
+);
+
+CREATE INDEX cluster_subscriptions_node ON cluster_subscriptions(node_id);
@@ -0,0 +1,2 @@
+-- какое именно подключение на узле: evict закрывает только его, а не переподключившегося заново
+ALTER TABLE cluster_sessions ADD COLUMN token BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,208 @@
+// Блокировка аккаунтов: users.status = active | suspended | blocked (+ status_reason, status_until).
+// status_until истек - аккаунт снова активен, отдельно снимать не нужно.
+// Проверяется при подключении (WS, TCP, HTTP) и в SQL выборках UDP / MQTT; живые сессии выкидывает suspend_user.
+
+use serde::Serialize;
+use serde_json::{Value, json};
+use sqlx::{PgPool, Row, postgres::PgRow};
+use std::sync::LazyLock;
+use crate::hub::UserId;
+use crate::presence::PRESENCE_COLUMNS;
+
+pub const STATUSES: [&str; 3] = ["active", "suspended", "blocked"];
+
+// условие для WHERE: аккаунт можно пускать
+pub const ACTIVE_SQL: &str = "(users.status = 'active' OR COALESCE(users.status_until <= now(), false))";
+
+// для admin_users / admin_user: status с истекшим until показываем как active
+pub const STATUS_COLUMNS: &str = r#"
+    CASE WHEN users.status_until <= now() THEN 'active' ELSE users.status END AS status,
+    users.status_reason,
+    EXTRACT(EPOCH FROM users.status_until)::BIGINT AS status_until
+"#;
+
+#[derive(Debug, Default, Serialize)]
+pub struct AccountStatus {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_until: Option<i64>,
+}
+
+impl AccountStatus {
+    // active - без reason / until, даже если в строке остались от истекшей блокировки
+    pub fn from_row(row: &PgRow) -> Self {
+        let status = row.try_get::<String, _>("status").unwrap_or_default();
+        if status == "active" {
+            return AccountStatus { status, ..Default::default() };
+        }
+        AccountStatus {
+            status,
+            status_reason: row.try_get("status_reason").unwrap_or(None),
+            status_until: row.try_get("status_until").unwrap_or(None),
+        }
+    }
+}
+
+pub fn status_json(out: &mut Value, row: &PgRow) {
+    if let (Some(o), Value::Object(status)) = (out.as_object_mut(), json!(AccountStatus::from_row(row))) {
+        o.extend(status);
+    }
+}
+
+// "suspended" или "blocked: stolen" - для close reason и ответа клиенту
+pub fn reason(status: &str, reason: Option<&str>) -> String {
+    match reason {
+        Some(r) if !r.is_empty() => format!("{}: {}", status, r),
+        _ => status.to_string(),
+    }
+}
+
+// логин по public_ed (WS, TCP, HTTP): ключи и блокировка одним запросом
+// status = NULL - пускать, иначе отказ с denied()
+pub static LOGIN_SQL: LazyLock<String> = LazyLock::new(|| format!(
+    "SELECT id, public_x, CASE WHEN {} THEN NULL ELSE users.status END, users.status_reason FROM users WHERE public_ed = $1",
+    ACTIVE_SQL));
+
+pub type LoginRow = (UserId, Vec<u8>, Option<String>, Option<String>);
+
+// None - пускать, Some(причина) - отказ
+pub fn denied(status: Option<String>, status_reason: Option<String>) -> Option<String> {
+    status.map(|s| reason(&s, status_reason.as_deref()))
+}
+
+// ================ общее для 0x00 (admin_*) и CLI (user ...) ================
+
+// для ILIKE: % и _ из поиска - буквально, не шаблон
+fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+// admin_users / `user list`: поиск по email / info / id, kind "user" (есть email) или "device",
+// status с учетом истекшего until; (total, строки страницы)
+pub async fn list(
+    pool: &PgPool,
+    search: Option<String>,
+    kind: Option<String>,
+    status: Option<String>,
+    offset: i64,
+    limit: i64,
+) -> Result<(i64, Vec<PgRow>), sqlx::Error> {
+    let search = search.filter(|s| !s.is_empty());
+    let pattern = search.as_deref().map(|s| format!("%{}%", like_escape(s)));
+    let filter = format!(r#"
+            WHERE ($1::TEXT IS NULL OR email ILIKE $2 ESCAPE '\' OR info::TEXT ILIKE $2 ESCAPE '\' OR id::TEXT = $1)
+              AND ($3::TEXT IS NULL OR ($3 = 'user') = (email IS NOT NULL))
+              AND ($4::TEXT IS NULL OR CASE WHEN {} THEN 'active' ELSE status END = $4)
+        "#, ACTIVE_SQL);
+    // отдельным запросом: COUNT(*) OVER () за концом списка дал бы 0
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM users {}", filter))
+        .bind(&search).bind(&pattern).bind(&kind).bind(&status)
+        .fetch_one(pool).await?;
+    let rows = sqlx::query(&format!(
+        r#"
+            SELECT id, email, info, admin_info->'created_by' AS created_by,
+                EXTRACT(EPOCH FROM time_reg)::BIGINT AS time_reg,
+                EXISTS(SELECT 1 FROM cluster_sessions c WHERE c.user_id = users.id) AS on_cluster,
+                {}, {}
+            FROM users LEFT JOIN presence p ON p.user_id = users.id
+            {}
+            ORDER BY id
+            OFFSET $5 LIMIT $6
+        "#, PRESENCE_COLUMNS, STATUS_COLUMNS, filter))
+        .bind(&search).bind(&pattern).bind(&kind).bind(&status).bind(offset).bind(limit)
+        .fetch_all(pool).await?;
+    Ok((total, rows))
+}
+
+// admin_user / `user show`: запись целиком - admin_info, ключи, роли, узел кластера
+pub async fn find(pool: &PgPool, id: UserId) -> Result<Option<PgRow>, sqlx::Error> {
+    sqlx::query(&format!(
+        r#"
+            SELECT email, info, admin_info, public_x, public_ed,
+                EXTRACT(EPOCH FROM time_reg)::BIGINT AS time_reg,
+                EXTRACT(EPOCH FROM time_upd)::BIGINT AS time_upd,
+                (SELECT node_id FROM cluster_sessions c WHERE c.user_id = users.id) AS node_id,
+                ARRAY(SELECT role FROM user_roles r WHERE r.user_id = users.id ORDER BY role) AS roles,
+                {}, {}
+            FROM users LEFT JOIN presence p ON p.user_id = users.id
+            WHERE id = $1
+        "#, PRESENCE_COLUMNS, STATUS_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool).await
+}
+
+// suspended / blocked; active - это unsuspend
+pub fn is_suspend_status(status: &str) -> bool {
+    status != "active" && STATUSES.contains(&status)
+}
+
+// false - нет такого; by = None - из CLI; живую сессию выкидывает вызывающий
+pub async fn suspend(
+    pool: &PgPool,
+    id: UserId,
+    status: &str,
+    reason: Option<&str>,
+    until: Option<i64>,
+    by: Option<UserId>,
+) -> Result<bool, sqlx::Error> {
+    let done = sqlx::query(r#"UPDATE users SET status = $1, status_reason = $2, status_until = to_timestamp($3), status_by = $4 WHERE id = $5"#)
+        .bind(status).bind(reason.filter(|r| !r.is_empty())).bind(until.map(|t| t as f64)).bind(by).bind(id)
+        .execute(pool).await?;
+    Ok(done.rows_affected() > 0)
+}
+
+pub async fn unsuspend(pool: &PgPool, id: UserId, by: Option<UserId>) -> Result<bool, sqlx::Error> {
+    let done = sqlx::query(r#"UPDATE users SET status = 'active', status_reason = NULL, status_until = NULL, status_by = $1 WHERE id = $2"#)
+        .bind(by).bind(id)
+        .execute(pool).await?;
+    Ok(done.rows_affected() > 0)
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reason_text() {
+        assert_eq!(reason("suspended", None), "suspended");
+        assert_eq!(reason("suspended", Some("")), "suspended");
+        assert_eq!(reason("blocked", Some("stolen")), "blocked: stolen");
+        assert_eq!(denied(None, Some("stale".into())), None);
+        assert_eq!(denied(Some("blocked".into()), Some("stolen".into())).as_deref(), Some("blocked: stolen"));
+        assert_eq!(like_escape(r"50%_a\b"), r"50\%\_a\\b");
+        assert!(is_suspend_status("blocked") && !is_suspend_status("active") && !is_suspend_status("deleted"));
+    }
+
+    // схема не нужна, только сервер: AG_POSTGRES=postgres://... cargo test status_until -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn status_until_expires() {
+        let pool = PgPool::connect(&std::env::var("AG_POSTGRES").unwrap()).await.unwrap();
+        let rows = sqlx::query(&format!(r#"
+            SELECT name, {} AS active, {}
+            FROM (VALUES
+                ('active',    'active',    NULL,     NULL::TIMESTAMPTZ),
+                ('forever',   'suspended', 'spam',   NULL),
+                ('later',     'blocked',   'stolen', now() + interval '1 hour'),
+                ('expired',   'suspended', 'spam',   now() - interval '1 second')
+            ) AS users(name, status, status_reason, status_until)
+            ORDER BY name
+            "#, ACTIVE_SQL, STATUS_COLUMNS))
+            .fetch_all(&pool).await.unwrap();
+
+        let got: Vec<(String, bool, String, Option<String>)> = rows.iter().map(|row| {
+            let s = AccountStatus::from_row(row);
+            (row.get("name"), row.get("active"), s.status, s.status_reason)
+        }).collect();
+        assert_eq!(got, vec![
+            ("active".into(), true, "active".into(), None),
+            ("expired".into(), true, "active".into(), None),
+            ("forever".into(), false, "suspended".into(), Some("spam".into())),
+            ("later".into(), false, "blocked".into(), Some("stolen".into())),
+        ]);
+    }
+}
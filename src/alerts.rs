@@ -0,0 +1,238 @@
+use serde_json::{Value, json};
+use sqlx::PgPool;
+
+use std::sync::Arc;
+use crate::email::escape_html;
+use crate::hub::{HubState, UserId};
+use crate::notify::notify_user;
+use crate::server::push;
+
+pub const OPS: [&str; 6] = [">", ">=", "<", "<=", "==", "!="];
+
+#[derive(sqlx::FromRow, serde::Serialize, Debug, Clone)]
+pub struct AlertRule {
+    pub id: i32,
+    pub device_id: UserId,
+    #[serde(skip)]
+    pub user_id: UserId,
+    pub name: String,
+    pub path: String,
+    pub op: String,
+    pub threshold: f64,
+    pub hysteresis: f64,
+    #[serde(rename = "cooldown")]
+    pub cooldown_sec: i32,
+    pub firing: bool,
+    pub last_fired: Option<i64>, // unixtime
+}
+
+pub const SELECT_RULES: &str = r#"
+    SELECT id, device_id, user_id, name, path, op, threshold, hysteresis, cooldown_sec, firing,
+        EXTRACT(EPOCH FROM last_fired)::BIGINT AS last_fired
+    FROM alert_rules
+"#;
+
+impl AlertRule {
+    pub fn to_json(&self) -> Value {
+        json!(self)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    Fire,  // условие наступило
+    Clear, // вернулось назад с учетом гистерезиса
+    Stay,
+}
+
+// "temp", "sensors.0.t" или JSON pointer "/sensors/0/t"
+pub fn json_number(payload: &Value, path: &str) -> Option<f64> {
+    if path.starts_with('/') {
+        return payload.pointer(path)?.as_f64();
+    }
+    let mut v = payload;
+    for key in path.split('.') {
+        v = match v {
+            Value::Array(a) => a.get(key.parse::<usize>().ok()?)?,
+            _ => v.get(key)?,
+        };
+    }
+    v.as_f64()
+}
+
+fn condition(op: &str, value: f64, threshold: f64) -> bool {
+    match op {
+        ">" => value > threshold,
+        ">=" => value >= threshold,
+        "<" => value < threshold,
+        "<=" => value <= threshold,
+        "==" => value == threshold,
+        "!=" => value != threshold,
+        _ => false,
+    }
+}
+
+// снялась ли тревога: значение ушло за порог дальше чем на hysteresis
+fn cleared(op: &str, value: f64, threshold: f64, hysteresis: f64) -> bool {
+    match op {
+        ">" | ">=" => value < threshold - hysteresis,
+        "<" | "<=" => value > threshold + hysteresis,
+        "==" => (value - threshold).abs() > hysteresis,
+        "!=" => (value - threshold).abs() <= hysteresis,
+        _ => true,
+    }
+}
+
+pub fn evaluate(rule: &AlertRule, value: f64, now: i64) -> Transition {
+    if rule.firing {
+        if cleared(&rule.op, value, rule.threshold, rule.hysteresis) {
+            return Transition::Clear;
+        }
+        return Transition::Stay;
+    }
+    if !condition(&rule.op, value, rule.threshold) {
+        return Transition::Stay;
+    }
+    match rule.last_fired {
+        Some(last) if now - last < rule.cooldown_sec as i64 => Transition::Stay,
+        _ => Transition::Fire,
+    }
+}
+
+// переход только если правило еще в том состоянии, что видел evaluate: две записи подряд
+// (по задаче на каждую) не должны дважды уведомить; cooldown проверяется и здесь
+const FIRE_SQL: &str = r#"UPDATE alert_rules SET firing = true, last_fired = now()
+    WHERE id = $1 AND NOT firing AND (last_fired IS NULL OR last_fired <= now() - make_interval(secs => cooldown_sec))
+    RETURNING id"#;
+const CLEAR_SQL: &str = "UPDATE alert_rules SET firing = false WHERE id = $1 AND firing RETURNING id";
+
+async fn transition(pool: &PgPool, sql: &str, rule_id: i32) -> bool {
+    match sqlx::query_scalar::<_, i32>(sql).bind(rule_id).fetch_optional(pool).await {
+        Ok(row) => row.is_some(),
+        Err(e) => {
+            tracing::warn!("alerts: DB error for rule {}: {:?}", rule_id, e);
+            false
+        }
+    }
+}
+
+// для письма: пользовательские name / path / op экранировать
+fn alert_email(rule: &AlertRule, device_id: UserId, value: f64) -> (String, String) {
+    let subject = format!("Aguardia alert: {}", if rule.name.is_empty() { &rule.path } else { &rule.name });
+    let html = format!(
+        "<p>Device <b>{}</b>: <b>{}</b> = <b>{}</b> ({} {})</p>",
+        device_id, escape_html(&rule.path), value, escape_html(&rule.op), rule.threshold
+    );
+    (subject, html)
+}
+
+// вызывается на каждую новую запись 0x10
+pub async fn check(pool: PgPool, hub_state: Arc<HubState>, device_id: UserId, payload: Value) {
+    let rules = sqlx::query_as::<_, AlertRule>(&format!("{} WHERE device_id = $1", SELECT_RULES))
+        .bind(device_id)
+        .fetch_all(&pool)
+        .await;
+    let rules = match rules {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::warn!("alerts: DB error for device {}: {:?}", device_id, e);
+            return;
+        }
+    };
+
+    let now = crate::crypto25519::get_unixtime() as i64;
+
+    for rule in rules {
+        let Some(value) = json_number(&payload, &rule.path) else {
+            continue;
+        };
+
+        match evaluate(&rule, value, now) {
+            Transition::Stay => {}
+
+            Transition::Fire => {
+                if !transition(&pool, FIRE_SQL, rule.id).await {
+                    continue; // уже сработало в соседней задаче
+                }
+                tracing::info!("alert {} fired on device {}: {} = {}", rule.id, device_id, rule.path, value);
+
+                let msg = json!({ "action": "alert", "rule": rule.to_json(), "value": value, "time": now });
+                let (subject, html) = alert_email(&rule, device_id, value);
+                crate::webhooks::dispatch(&pool, device_id, "alert", msg.clone()).await;
+                notify_user(&pool, &hub_state, rule.user_id, &subject, &html, &msg).await;
+            }
+
+            Transition::Clear => {
+                if !transition(&pool, CLEAR_SQL, rule.id).await {
+                    continue;
+                }
+                let msg = json!({ "action": "alert_clear", "rule": rule.to_json(), "value": value, "time": now });
+                crate::webhooks::dispatch(&pool, device_id, "alert_clear", msg.clone()).await;
+                push(&hub_state, rule.user_id, &msg).await;
+            }
+        }
+    }
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(op: &str, threshold: f64, hysteresis: f64, cooldown_sec: i32) -> AlertRule {
+        AlertRule {
+            id: 1,
+            device_id: 2,
+            user_id: 1,
+            name: "freezer".into(),
+            path: "temp".into(),
+            op: op.into(),
+            threshold,
+            hysteresis,
+            cooldown_sec,
+            firing: false,
+            last_fired: None,
+        }
+    }
+
+    #[test]
+    fn json_number_paths() {
+        let payload = json!({"temp": -12.5, "sensors": [{"t": 3}, {"t": 4.5}], "name": "x"});
+        assert_eq!(json_number(&payload, "temp"), Some(-12.5));
+        assert_eq!(json_number(&payload, "sensors.1.t"), Some(4.5));
+        assert_eq!(json_number(&payload, "/sensors/0/t"), Some(3.0));
+        assert_eq!(json_number(&payload, "name"), None);
+        assert_eq!(json_number(&payload, "sensors.5.t"), None);
+    }
+
+    #[test]
+    fn fire_and_clear_with_hysteresis() {
+        let mut r = rule(">", -10.0, 1.0, 0);
+        assert_eq!(evaluate(&r, -12.0, 100), Transition::Stay);
+        assert_eq!(evaluate(&r, -9.5, 100), Transition::Fire);
+
+        r.firing = true;
+        r.last_fired = Some(100);
+        assert_eq!(evaluate(&r, -10.5, 110), Transition::Stay); // внутри гистерезиса
+        assert_eq!(evaluate(&r, -11.5, 120), Transition::Clear);
+    }
+
+    #[test]
+    fn cooldown_suppresses_refire() {
+        let mut r = rule("<", 5.0, 0.0, 60);
+        r.last_fired = Some(1000);
+        assert_eq!(evaluate(&r, 4.0, 1030), Transition::Stay);
+        assert_eq!(evaluate(&r, 4.0, 1060), Transition::Fire);
+    }
+
+    #[test]
+    fn email_escapes_user_input() {
+        let mut r = rule("<", 5.0, 0.0, 0);
+        r.path = "<img src=x onerror=alert(1)>".into();
+        let (_, html) = alert_email(&r, 2, 4.0);
+        assert!(!html.contains("<img"));
+        assert!(html.contains("&lt;img src=x onerror=alert(1)&gt;"));
+        assert!(html.contains("(&lt; 5)"));
+    }
+}
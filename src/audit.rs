@@ -0,0 +1,145 @@
+// Журнал безопасности (audit_log): логины, смена ключей, провалы подписи, create/delete устройств и данных,
+// действия админов, отказы маршрутизации, баны.
+// Пишется в фоне - обработчик не ждет БД; ошибка записи только в лог.
+// Повторяющиеся отказы (реконнект забаненного, флуд relay) - write_coalesced: одна запись на key за окно.
+
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry as MapEntry;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use crate::config::CONFIG;
+use crate::hub::UserId;
+
+pub const OK: &str = "ok";
+pub const DENIED: &str = "denied";
+pub const FAILED: &str = "failed";
+
+// key -> (конец окна, сколько пропущено в окне)
+static RECENT: LazyLock<DashMap<String, (Instant, u64)>> = LazyLock::new(DashMap::new);
+const RECENT_MAX: usize = 10_000;
+
+#[derive(Debug, Default)]
+pub struct Entry {
+    pub actor: Option<UserId>,
+    pub target: Option<UserId>,
+    pub action: String,
+    pub ip: Option<String>,
+    pub result: &'static str,
+    pub details: Value,
+}
+
+pub fn write(pool: &PgPool, e: Entry) {
+    let pool = pool.clone();
+    tracing::info!(actor = ?e.actor, target = ?e.target, ip = ?e.ip, result = e.result, "audit: {}", e.action);
+    crate::shutdown::spawn(async move {
+        if let Err(err) = insert(&pool, e).await {
+            tracing::error!("audit: insert failed: {:?}", err);
+        }
+    });
+}
+
+// одна запись на key за window; пропущенные - в metrics и details.repeated следующей записи по этому key
+pub fn write_coalesced(pool: &PgPool, key: String, window: Duration, mut e: Entry) {
+    let now = Instant::now();
+    if RECENT.len() > RECENT_MAX {
+        RECENT.retain(|_, (until, _)| *until > now);
+    }
+    let Some(repeated) = coalesce(&RECENT, key, window, now) else {
+        crate::metrics::inc(&crate::metrics::AUDIT_COALESCED);
+        return;
+    };
+    if repeated > 0 {
+        if e.details.is_null() {
+            e.details = json!({});
+        }
+        e.details["repeated"] = json!(repeated);
+    }
+    write(pool, e);
+}
+
+// Some(пропущено с прошлой записи) - писать, None - окно еще не кончилось
+fn coalesce(recent: &DashMap<String, (Instant, u64)>, key: String, window: Duration, now: Instant) -> Option<u64> {
+    match recent.entry(key) {
+        MapEntry::Vacant(v) => {
+            v.insert((now + window, 0));
+            Some(0)
+        }
+        MapEntry::Occupied(mut o) => {
+            let (until, skipped) = o.get_mut();
+            if now < *until {
+                *skipped += 1;
+                return None;
+            }
+            let repeated = *skipped;
+            *o.get_mut() = (now + window, 0);
+            Some(repeated)
+        }
+    }
+}
+
+// с ожиданием: CLI завершается сразу, фоновая запись не успела бы
+pub async fn insert(pool: &PgPool, e: Entry) -> Result<(), sqlx::Error> {
+    let details = if e.details.is_null() { json!({}) } else { e.details };
+    sqlx::query(
+        "INSERT INTO audit_log (actor, target, action, ip, result, details) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(e.actor).bind(e.target).bind(&e.action).bind(&e.ip).bind(e.result).bind(details)
+        .execute(pool).await?;
+    Ok(())
+}
+
+// retention: раз в час удалить старше audit_retention_days (0 = хранить всё)
+pub fn start(pool: PgPool) {
+    if CONFIG.audit_retention_days == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            ticker.tick().await;
+            if crate::shutdown::is_shutting_down() {
+                break;
+            }
+            let _in_flight = crate::shutdown::in_flight();
+            match retention(&pool, CONFIG.audit_retention_days).await {
+                Ok(n) if n > 0 => tracing::info!("audit: {} old records removed", n),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("audit: retention failed: {:?}", e),
+            }
+        }
+    });
+}
+
+// триггер audit_log_no_delete пропускает только строки старше aguardia.audit_retention_days (migrations/0013)
+async fn retention(pool: &PgPool, days: u32) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT set_config('aguardia.audit_retention_days', $1, true)")
+        .bind(days.to_string())
+        .execute(&mut tx).await?;
+    let r = sqlx::query("DELETE FROM audit_log WHERE time < now() - make_interval(days => $1)")
+        .bind(days as i32)
+        .execute(&mut tx).await?;
+    tx.commit().await?;
+    Ok(r.rows_affected())
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce_one_per_window() {
+        let recent = DashMap::new();
+        let t0 = Instant::now();
+        let w = Duration::from_secs(300);
+        assert_eq!(coalesce(&recent, "banned:1.2.3.4".into(), w, t0), Some(0));
+        assert_eq!(coalesce(&recent, "banned:1.2.3.4".into(), w, t0 + Duration::from_secs(1)), None);
+        assert_eq!(coalesce(&recent, "banned:1.2.3.4".into(), w, t0 + Duration::from_secs(299)), None);
+        assert_eq!(coalesce(&recent, "banned:5.6.7.8".into(), w, t0), Some(0)); // другой key - свое окно
+        assert_eq!(coalesce(&recent, "banned:1.2.3.4".into(), w, t0 + w), Some(2));
+        assert_eq!(coalesce(&recent, "banned:1.2.3.4".into(), w, t0 + w * 2), Some(0));
+    }
+}
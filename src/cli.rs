@@ -0,0 +1,336 @@
+// Управление из командной строки: aguardia_pulse <команда>; без команды - serve, как раньше.
+// Ходит прямо в БД из etc/config.toml / AG_*, сервер для этого не нужен.
+// Вывод - JSON, одна строка на запись (удобно в jq); ошибки - в stderr и код выхода 1.
+
+use clap::{Parser, Subcommand};
+use futures::TryStreamExt;
+use hex::FromHex;
+use serde_json::{Value, json};
+use sqlx::{PgPool, Row, postgres::PgPoolOptions};
+use std::io::Write;
+use crate::accounts::{self, status_json};
+use crate::audit;
+use crate::config::CONFIG;
+use crate::crypto25519::{self, ed25519_public, ed25519_secret, x25519_public, x25519_secret};
+use crate::hub::UserId;
+use crate::presence::presence_json;
+use crate::protocol;
+use crate::roles;
+
+#[derive(Parser)]
+#[command(version, about = "Aguardia server and management commands")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server (default)
+    Serve,
+    /// Generate new server seeds (AG_SEED_X, AG_SEED_ED) and print the derived public keys
+    Keygen,
+    /// Apply database migrations and exit
+    Migrate,
+    /// Users and devices: list, show, suspend
+    #[command(subcommand)]
+    User(UserCmd),
+    /// Provision devices
+    #[command(subcommand)]
+    Device(DeviceCmd),
+    /// Roles (see migrations/0010_roles.sql)
+    #[command(subcommand)]
+    Admin(AdminCmd),
+    /// Dump data as JSON lines
+    #[command(subcommand)]
+    Export(ExportCmd),
+}
+
+#[derive(Subcommand)]
+pub enum UserCmd {
+    /// List users and devices
+    List {
+        /// Match email, info or id
+        #[arg(long)]
+        search: Option<String>,
+        /// active, suspended or blocked
+        #[arg(long)]
+        status: Option<String>,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Full record, with admin_info and roles
+    Show { id: UserId },
+    /// Deny access without deleting data
+    Suspend {
+        id: UserId,
+        /// suspended or blocked
+        #[arg(long, default_value = "suspended")]
+        status: String,
+        #[arg(long)]
+        reason: Option<String>,
+        /// Unixtime; without it - until `user unsuspend`
+        #[arg(long)]
+        until: Option<i64>,
+    },
+    Unsuspend { id: UserId },
+}
+
+#[derive(Subcommand)]
+pub enum DeviceCmd {
+    /// Register a device; keys are generated unless --x and --ed are given
+    Create {
+        name: String,
+        /// User the device shows up for in my_devices
+        #[arg(long)]
+        owner: Option<UserId>,
+        /// Public X25519 key, hex
+        #[arg(long, requires = "ed")]
+        x: Option<String>,
+        /// Public Ed25519 key, hex
+        #[arg(long, requires = "x")]
+        ed: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AdminCmd {
+    /// Give a role: admin, support, provisioner, ...
+    Grant { user_id: UserId, role: String },
+    Revoke { user_id: UserId, role: String },
+}
+
+#[derive(Subcommand)]
+pub enum ExportCmd {
+    /// Telemetry: {"id","device_id","time","payload"} per line, oldest first
+    Data {
+        #[arg(long)]
+        device: Option<UserId>,
+        /// Unixtime, inclusive
+        #[arg(long)]
+        since: Option<i64>,
+        /// Unixtime, exclusive
+        #[arg(long)]
+        until: Option<i64>,
+    },
+}
+
+fn print(v: &Value) {
+    println!("{}", v);
+}
+
+// сиды как у сервера: из seed_x - X25519, из seed_ed - Ed25519
+fn public_keys(seed_x: &[u8; 32], seed_ed: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let x = x25519_public(&x25519_secret(seed_x));
+    let ed = ed25519_public(&ed25519_secret(seed_ed)).to_bytes();
+    (x, ed)
+}
+
+pub fn keygen() {
+    let (seed_x, seed_ed) = (crypto25519::seed(), crypto25519::seed());
+    let (x, ed) = public_keys(&seed_x, &seed_ed);
+    println!("AG_SEED_X={}", hex::encode_upper(seed_x));
+    println!("AG_SEED_ED={}", hex::encode_upper(seed_ed));
+    println!("# public_x={}", hex::encode_upper(x));
+    println!("# public_ed={}", hex::encode_upper(ed));
+}
+
+async fn pool() -> anyhow::Result<PgPool> {
+    Ok(PgPoolOptions::new()
+        .max_connections(2)
+        .acquire_timeout(std::time::Duration::from_secs(CONFIG.db_acquire_timeout_sec))
+        .connect(CONFIG.postgres.as_str()).await?)
+}
+
+// в audit_log: actor нет, via = cli
+async fn audit(pool: &PgPool, action: &str, target: Option<UserId>, details: Value) -> anyhow::Result<()> {
+    let mut details = details;
+    details["via"] = json!("cli");
+    audit::insert(pool, audit::Entry { target, action: action.into(), result: audit::OK, details, ..Default::default() }).await?;
+    Ok(())
+}
+
+pub async fn run(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled in main"),
+        Command::Keygen => keygen(),
+        Command::Migrate => {
+            let pool = pool().await?;
+            crate::MIGRATOR.run(&pool).await?;
+            let applied: Vec<(i64, String)> = sqlx::query_as("SELECT version, description FROM _sqlx_migrations ORDER BY version")
+                .fetch_all(&pool).await?;
+            for (version, description) in applied {
+                print(&json!({ "version": version, "description": description }));
+            }
+        }
+        Command::User(cmd) => user(&pool().await?, cmd).await?,
+        Command::Device(cmd) => device(&pool().await?, cmd).await?,
+        Command::Admin(cmd) => admin(&pool().await?, cmd).await?,
+        Command::Export(cmd) => export(&pool().await?, cmd).await?,
+    }
+    Ok(())
+}
+
+async fn user(pool: &PgPool, cmd: UserCmd) -> anyhow::Result<()> {
+    match cmd {
+        UserCmd::List { search, status, limit } => {
+            let (_, rows) = accounts::list(pool, search, None, status, 0, limit).await?;
+            for row in rows {
+                let email = row.try_get::<Option<String>, _>("email")?;
+                let mut v = presence_json(&row);
+                v["id"] = json!(row.try_get::<i32, _>("id")?);
+                v["kind"] = json!(if email.is_some() { "user" } else { "device" });
+                v["email"] = json!(email);
+                v["info"] = row.try_get::<Value, _>("info")?;
+                v["time_reg"] = json!(row.try_get::<i64, _>("time_reg")?);
+                status_json(&mut v, &row);
+                print(&v);
+            }
+        }
+
+        UserCmd::Show { id } => {
+            let row = accounts::find(pool, id).await?
+                .ok_or_else(|| anyhow::anyhow!("user not found"))?;
+            let mut v = presence_json(&row);
+            v["id"] = json!(id);
+            v["email"] = json!(row.try_get::<Option<String>, _>("email")?);
+            v["info"] = row.try_get::<Value, _>("info")?;
+            v["admin_info"] = row.try_get::<Value, _>("admin_info")?;
+            v["public_x"] = json!(hex::encode_upper(row.try_get::<Vec<u8>, _>("public_x")?));
+            v["public_ed"] = json!(hex::encode_upper(row.try_get::<Vec<u8>, _>("public_ed")?));
+            v["time_reg"] = json!(row.try_get::<i64, _>("time_reg")?);
+            v["time_upd"] = json!(row.try_get::<i64, _>("time_upd")?);
+            v["node_id"] = json!(row.try_get::<Option<String>, _>("node_id")?);
+            v["roles"] = json!(row.try_get::<Vec<String>, _>("roles")?);
+            v["superuser"] = json!(CONFIG.admins.contains(&id));
+            status_json(&mut v, &row);
+            print(&v);
+        }
+
+        UserCmd::Suspend { id, status, reason, until } => {
+            if !accounts::is_suspend_status(&status) {
+                anyhow::bail!("bad status: {}", status);
+            }
+            if !accounts::suspend(pool, id, &status, reason.as_deref(), until, None).await? {
+                anyhow::bail!("user not found");
+            }
+            // без кластера чужого процесса не достать: живая сессия доживет до переподключения
+            let kicked = crate::cluster::kick_remote(pool, id, &accounts::reason(&status, reason.as_deref())).await?;
+            if !kicked && !CONFIG.cluster {
+                eprintln!("note: live session (if any) is not kicked without cluster mode, use the suspend_user action");
+            }
+            audit(pool, "suspend_user", Some(id), json!({ "status": status, "reason": reason, "until": until })).await?;
+            print(&json!({ "kicked": kicked }));
+        }
+
+        UserCmd::Unsuspend { id } => {
+            if !accounts::unsuspend(pool, id, None).await? {
+                anyhow::bail!("user not found");
+            }
+            audit(pool, "unsuspend_user", Some(id), json!({})).await?;
+            print(&json!(true));
+        }
+    }
+    Ok(())
+}
+
+async fn device(pool: &PgPool, cmd: DeviceCmd) -> anyhow::Result<()> {
+    let DeviceCmd::Create { name, owner, x, ed } = cmd;
+
+    // ключи даны - только публичные; нет - генерируем и печатаем сиды (больше их нигде не будет)
+    let (x, ed, seeds) = match (x, ed) {
+        (Some(x), Some(ed)) => {
+            let x = <[u8; 32]>::from_hex(&x).map_err(|_| anyhow::anyhow!("bad x"))?;
+            let ed = <[u8; 32]>::from_hex(&ed).map_err(|_| anyhow::anyhow!("bad ed"))?;
+            ed25519_dalek::VerifyingKey::from_bytes(&ed).map_err(|_| anyhow::anyhow!("bad ed"))?;
+            (x, ed, None)
+        }
+        _ => {
+            let (seed_x, seed_ed) = (crypto25519::seed(), crypto25519::seed());
+            let (x, ed) = public_keys(&seed_x, &seed_ed);
+            (x, ed, Some((seed_x, seed_ed)))
+        }
+    };
+
+    let row: Option<(i32,)> = sqlx::query_as(
+        r#"INSERT INTO users (public_x, public_ed, info, admin_info) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING RETURNING id"#)
+        .bind(&x[..]).bind(&ed[..])
+        .bind(json!({ "name": name }))
+        .bind(json!({ "created_by": owner, "name": name }))
+        .fetch_optional(pool).await?;
+    let Some((id,)) = row else {
+        anyhow::bail!("already_exists");
+    };
+    audit(pool, "create_new_device", Some(id), json!({ "owner": owner })).await?;
+
+    let mut out = json!({ "id": id, "public_x": hex::encode_upper(x), "public_ed": hex::encode_upper(ed) });
+    if let Some((seed_x, seed_ed)) = seeds {
+        out["seed_x"] = json!(hex::encode_upper(seed_x));
+        out["seed_ed"] = json!(hex::encode_upper(seed_ed));
+    }
+    print(&out);
+    Ok(())
+}
+
+async fn admin(pool: &PgPool, cmd: AdminCmd) -> anyhow::Result<()> {
+    match cmd {
+        AdminCmd::Grant { user_id, role } => {
+            roles::grant(pool, user_id, &role, None).await
+                .map_err(|e| match e {
+                    protocol::Error::NotFound("role") => anyhow::anyhow!("role not found: {}", role),
+                    e => anyhow::anyhow!(e.message()),
+                })?;
+            audit(pool, "grant_role", Some(user_id), json!({ "role": role })).await?;
+            print(&json!(true));
+        }
+        AdminCmd::Revoke { user_id, role } => {
+            let done = roles::revoke(pool, user_id, &role).await?;
+            audit(pool, "revoke_role", Some(user_id), json!({ "role": role })).await?;
+            print(&json!(done));
+        }
+    }
+    Ok(())
+}
+
+async fn export(pool: &PgPool, cmd: ExportCmd) -> anyhow::Result<()> {
+    let ExportCmd::Data { device, since, until } = cmd;
+    // потоком: таблица может быть больше памяти
+    let mut rows = sqlx::query(
+        r#"
+            SELECT id, device_id, payload, EXTRACT(EPOCH FROM time)::BIGINT AS time
+            FROM data
+            WHERE ($1::INT IS NULL OR device_id = $1)
+              AND ($2::FLOAT8 IS NULL OR time >= to_timestamp($2))
+              AND ($3::FLOAT8 IS NULL OR time < to_timestamp($3))
+            ORDER BY time, id
+        "#)
+        .bind(device).bind(since.map(|t| t as f64)).bind(until.map(|t| t as f64))
+        .fetch(pool);
+
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    while let Some(row) = rows.try_next().await? {
+        writeln!(out, "{}", json!({
+            "id": row.try_get::<i64, _>("id")?,
+            "device_id": row.try_get::<i32, _>("device_id")?,
+            "time": row.try_get::<i64, _>("time")?,
+            "payload": row.try_get::<Value, _>("payload")?,
+        }))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_definition() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+        assert!(Cli::try_parse_from(["aguardia_pulse"]).unwrap().command.is_none());
+        assert!(Cli::try_parse_from(["aguardia_pulse", "device", "create", "d", "--x", "00"]).is_err()); // --x без --ed
+    }
+}
@@ -0,0 +1,400 @@
+// Несколько серверов за балансировщиком.
+//
+// cluster_sessions (Postgres) - кто на каком узле подключен. Доставка на чужой узел - NOTIFY:
+//   канал узла "aguardia_node_<node_id>":
+//     {"kind":"deliver","to":id,"data":"<base64>"}   - отдать фрейм своей сессии
+//     {"kind":"evict","user_id":id,"token":n}        - он переподключился на другой узел, старую сессию (token) закрыть
+//     {"kind":"kick","user_id":id,"reason":"..."}    - админ выкинул сессию (admin_kick на любом узле)
+//   общий канал "aguardia_cluster":
+//     {"kind":"data","from":"<node_id>","device_id":id,"msg":{...}} - телеметрия для подписчиков на других узлах
+//   data шлется, только если в cluster_subscriptions есть другой узел с подписчиками этого устройства.
+// NOTIFY ограничен ~8000 байт, фреймы больше по кластеру не ходят.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::config::CONFIG;
+use crate::crypto25519;
+use crate::hub::{HubState, Outgoing, UserId};
+
+const BROADCAST: &str = "aguardia_cluster";
+const MAX_NOTIFY: usize = 7900;
+const NODE_TIMEOUT_SEC: i64 = 60;
+
+// из HubState::add / del
+#[derive(Debug)]
+pub enum Event {
+    Connected(UserId, u64, [u8; 32], [u8; 32]), // + token подключения, public_x, public_ed
+    Disconnected(UserId),
+    Watched(UserId, bool), // у устройства появились (true) / кончились (false) подписчики на этом узле
+    Rejoin, // из heartbeat: записей узла в БД нет, вернуть туда все локальные
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Message {
+    Deliver { to: UserId, data: String }, // base64 фрейма
+    // token: None - от узла старой версии, любое подключение
+    Evict { user_id: UserId, #[serde(default, skip_serializing_if = "Option::is_none")] token: Option<u64> },
+    Kick { user_id: UserId, #[serde(default = "kicked")] reason: String },
+    Data { from: String, device_id: UserId, msg: Value },
+}
+
+fn kicked() -> String {
+    "kicked".into()
+}
+
+impl Message {
+    // None - не влезает в NOTIFY
+    fn encode(&self) -> Option<String> {
+        let payload = serde_json::to_string(self).ok()?;
+        (payload.len() <= MAX_NOTIFY).then_some(payload)
+    }
+
+    fn decode(payload: &str) -> Option<Message> {
+        serde_json::from_str(payload).ok()
+    }
+}
+
+pub struct Cluster {
+    pool: PgPool,
+    pub node_id: String,
+}
+
+static CLUSTER: OnceLock<Cluster> = OnceLock::new();
+
+// None - работаем одним узлом
+pub fn get() -> Option<&'static Cluster> {
+    CLUSTER.get()
+}
+
+fn channel(node_id: &str) -> String {
+    format!("aguardia_node_{}", node_id)
+}
+
+// имя канала LISTEN - идентификатор, поэтому только [a-z0-9_]
+fn node_id() -> String {
+    let id: String = CONFIG.node_id.to_lowercase().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if id.is_empty() { format!("{:08x}", rand::random::<u32>()) } else { id }
+}
+
+pub async fn start(pool: PgPool, hub_state: Arc<HubState>) -> anyhow::Result<()> {
+    let node_id = node_id();
+
+    // наши записи от прошлого запуска (упали, не убрали за собой)
+    sqlx::query("DELETE FROM cluster_sessions WHERE node_id = $1").bind(&node_id).execute(&pool).await?;
+    sqlx::query("DELETE FROM cluster_subscriptions WHERE node_id = $1").bind(&node_id).execute(&pool).await?;
+    sqlx::query("INSERT INTO cluster_nodes (node_id) VALUES ($1) ON CONFLICT (node_id) DO UPDATE SET last_beat = now()")
+        .bind(&node_id).execute(&pool).await?;
+
+    let mut listener = PgListener::connect_with(&pool).await?;
+    listener.listen_all([channel(&node_id).as_str(), BROADCAST]).await?;
+
+    let _ = CLUSTER.set(Cluster { pool: pool.clone(), node_id: node_id.clone() });
+    tracing::info!("Cluster: node {}", node_id);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    hub_state.set_cluster(tx.clone());
+    tokio::spawn(registry(pool.clone(), node_id.clone(), hub_state.clone(), rx));
+    tokio::spawn(heartbeat(pool, node_id.clone(), tx));
+
+    tokio::spawn(async move {
+        loop {
+            match listener.recv().await {
+                Ok(n) => match Message::decode(n.payload()) {
+                    Some(m) => incoming(&hub_state, &node_id, m).await,
+                    None => tracing::warn!("cluster: bad payload on {}", n.channel()),
+                },
+                Err(e) => {
+                    tracing::warn!("cluster: listener error: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// подключения/отключения локальных сессий -> cluster_sessions, подписки -> cluster_subscriptions
+// Rejoin идет через ту же очередь: снимок сессий не обгонит их Disconnected
+async fn registry(pool: PgPool, node_id: String, hub_state: Arc<HubState>, mut rx: mpsc::UnboundedReceiver<Event>) {
+    while let Some(ev) = rx.recv().await {
+        let result = match ev {
+            Event::Connected(id, token, x, ed) => connected(&pool, &node_id, id, token, &x, &ed).await,
+            Event::Disconnected(id) => {
+                // только если за ним еще числимся мы - он мог уже переехать
+                sqlx::query("DELETE FROM cluster_sessions WHERE user_id = $1 AND node_id = $2")
+                    .bind(id).bind(&node_id).execute(&pool).await.map(|_| ())
+            }
+            Event::Watched(device_id, true) => {
+                sqlx::query("INSERT INTO cluster_subscriptions (node_id, device_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+                    .bind(&node_id).bind(device_id).execute(&pool).await.map(|_| ())
+            }
+            Event::Watched(device_id, false) => {
+                sqlx::query("DELETE FROM cluster_subscriptions WHERE node_id = $1 AND device_id = $2")
+                    .bind(&node_id).bind(device_id).execute(&pool).await.map(|_| ())
+            }
+            Event::Rejoin => rejoin(&pool, &node_id, &hub_state).await,
+        };
+        if let Err(e) = result {
+            tracing::warn!("cluster: registry DB error: {:?}", e);
+        }
+    }
+}
+
+async fn connected(pool: &PgPool, node_id: &str, id: UserId, token: u64, x: &[u8; 32], ed: &[u8; 32]) -> Result<(), sqlx::Error> {
+    let (old, old_token): (Option<String>, Option<i64>) = sqlx::query_as(r#"
+        WITH old AS (SELECT node_id, token FROM cluster_sessions WHERE user_id = $1)
+        INSERT INTO cluster_sessions (user_id, node_id, public_x, public_ed, token) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (user_id) DO UPDATE SET node_id = $2, public_x = $3, public_ed = $4, token = $5, connected_at = now()
+        RETURNING (SELECT node_id FROM old), (SELECT token FROM old)
+    "#)
+        .bind(id).bind(node_id).bind(&x[..]).bind(&ed[..]).bind(token as i64)
+        .fetch_one(pool).await?;
+
+    // закрыть там именно то подключение: если он уже вернулся туда заново, новое не трогать
+    if let Some(old) = old.filter(|o| o != node_id) {
+        tracing::info!("cluster: {} moved from node {}, evicting", id, old);
+        let token = old_token.map(|t| t as u64);
+        notify(pool, &channel(&old), &Message::Evict { user_id: id, token }).await?;
+    }
+    Ok(())
+}
+
+// нас вычистили по таймауту (не было связи с БД дольше NODE_TIMEOUT_SEC), а сессии живы
+// кто за это время подключился к другому узлу - ту запись не трогаем
+async fn rejoin(pool: &PgPool, node_id: &str, hub_state: &HubState) -> Result<(), sqlx::Error> {
+    let (sessions, watched) = hub_state.cluster_snapshot();
+    tracing::warn!("cluster: node {} was expired, re-registering {} sessions", node_id, sessions.len());
+    for (id, token, x, ed) in sessions {
+        sqlx::query(r#"INSERT INTO cluster_sessions (user_id, node_id, public_x, public_ed, token) VALUES ($1, $2, $3, $4, $5)
+                       ON CONFLICT (user_id) DO NOTHING"#)
+            .bind(id).bind(node_id).bind(&x[..]).bind(&ed[..]).bind(token as i64)
+            .execute(pool).await?;
+    }
+    for device_id in watched {
+        sqlx::query("INSERT INTO cluster_subscriptions (node_id, device_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+            .bind(node_id).bind(device_id).execute(pool).await?;
+    }
+    Ok(())
+}
+
+// last_beat своего узла + чистка сессий узлов, которые пропали не попрощавшись
+// своей записи нет (вычистили, пока не было связи с БД) - создать заново и вернуть сессии через registry
+async fn heartbeat(pool: PgPool, node_id: String, tx: mpsc::UnboundedSender<Event>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        ticker.tick().await;
+        if crate::shutdown::is_shutting_down() {
+            break;
+        }
+        let result = async {
+            let inserted: bool = sqlx::query_scalar(
+                "INSERT INTO cluster_nodes (node_id) VALUES ($1) ON CONFLICT (node_id) DO UPDATE SET last_beat = now() RETURNING xmax = 0")
+                .bind(&node_id).fetch_one(&pool).await?;
+            if inserted {
+                let _ = tx.send(Event::Rejoin);
+            }
+            for table in ["cluster_sessions", "cluster_subscriptions"] {
+                sqlx::query(&format!(r#"
+                    DELETE FROM {} WHERE node_id IN
+                        (SELECT node_id FROM cluster_nodes WHERE last_beat < now() - make_interval(secs => $1))
+                "#, table)).bind(NODE_TIMEOUT_SEC as f64).execute(&pool).await?;
+            }
+            sqlx::query("DELETE FROM cluster_nodes WHERE last_beat < now() - make_interval(secs => $1)")
+                .bind(NODE_TIMEOUT_SEC as f64).execute(&pool).await?;
+            Ok::<_, sqlx::Error>(())
+        }.await;
+        if let Err(e) = result {
+            tracing::warn!("cluster: heartbeat DB error: {:?}", e);
+        }
+    }
+}
+
+// shutdown: убрать за собой, не дожидаясь NODE_TIMEOUT_SEC на других узлах
+pub async fn leave() {
+    let Some(c) = get() else { return };
+    let _ = sqlx::query("DELETE FROM cluster_sessions WHERE node_id = $1").bind(&c.node_id).execute(&c.pool).await;
+    let _ = sqlx::query("DELETE FROM cluster_subscriptions WHERE node_id = $1").bind(&c.node_id).execute(&c.pool).await;
+    let _ = sqlx::query("DELETE FROM cluster_nodes WHERE node_id = $1").bind(&c.node_id).execute(&c.pool).await;
+}
+
+// false - сообщение больше MAX_NOTIFY, не отправлено
+async fn notify(pool: &PgPool, channel: &str, msg: &Message) -> Result<bool, sqlx::Error> {
+    let Some(payload) = msg.encode() else {
+        return Ok(false);
+    };
+    sqlx::query("SELECT pg_notify($1, $2)").bind(channel).bind(payload).execute(pool).await?;
+    Ok(true)
+}
+
+async fn incoming(hub_state: &Arc<HubState>, node_id: &str, msg: Message) {
+    match msg {
+        Message::Deliver { to, data } => {
+            let Ok(bytes) = crypto25519::base64_to_bin(&data) else { return };
+            if !crate::hub::send_local(hub_state, to, Outgoing::Binary(bytes)).await {
+                tracing::debug!("cluster: {} is not here anymore", to);
+            }
+        }
+        Message::Evict { user_id, token } => hub_state.evict(user_id, token, "session moved to another server").await,
+        Message::Kick { user_id, reason } => {
+            hub_state.kick(user_id, &reason).await;
+        }
+        Message::Data { from, device_id, msg } => {
+            if from == node_id {
+                return; // свое
+            }
+            for sid in hub_state.subscribers(device_id) {
+                crate::server::push(hub_state, sid, &msg).await;
+            }
+        }
+    }
+}
+
+impl Cluster {
+    // узел и X25519 ключ пользователя, подключенного к другому узлу
+    pub async fn remote(&self, user_id: UserId) -> Option<(String, [u8; 32], [u8; 32])> {
+        let row: Option<(String, Vec<u8>, Vec<u8>)> = sqlx::query_as(
+            "SELECT node_id, public_x, public_ed FROM cluster_sessions WHERE user_id = $1 AND node_id <> $2")
+            .bind(user_id).bind(&self.node_id)
+            .fetch_optional(&self.pool).await
+            .unwrap_or_else(|e| {
+                tracing::warn!("cluster: DB error: {:?}", e);
+                None
+            });
+        let (node, x, ed) = row?;
+        Some((node, x.try_into().ok()?, ed.try_into().ok()?))
+    }
+
+    pub async fn forward(&self, to: UserId, msg: Outgoing) -> bool {
+        let Outgoing::Binary(bytes) = msg else {
+            return false;
+        };
+        let Some((node, _, _)) = self.remote(to).await else {
+            return false;
+        };
+        let msg = Message::Deliver { to, data: crypto25519::bin_to_base64(&bytes) };
+        match notify(&self.pool, &channel(&node), &msg).await {
+            Ok(true) => true,
+            Ok(false) => {
+                tracing::warn!("cluster: frame for {} too large to forward", to);
+                false
+            }
+            Err(e) => {
+                tracing::warn!("cluster: forward to {} failed: {:?}", node, e);
+                false
+            }
+        }
+    }
+
+    // телеметрия устройства - подписчикам на остальных узлах, если они там есть
+    pub async fn broadcast_data(&self, device_id: UserId, msg: &Value) {
+        let watched = sqlx::query_scalar::<_, bool>(
+            "SELECT EXISTS(SELECT 1 FROM cluster_subscriptions WHERE device_id = $1 AND node_id <> $2)")
+            .bind(device_id).bind(&self.node_id)
+            .fetch_one(&self.pool).await;
+        match watched {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                tracing::warn!("cluster: DB error: {:?}", e);
+                return;
+            }
+        }
+        let msg = Message::Data { from: self.node_id.clone(), device_id, msg: msg.clone() };
+        match notify(&self.pool, BROADCAST, &msg).await {
+            Ok(true) => {}
+            Ok(false) => tracing::debug!("cluster: data of {} too large to broadcast", device_id),
+            Err(e) => tracing::warn!("cluster: broadcast failed: {:?}", e),
+        }
+    }
+}
+
+// закрыть сессию, где бы она ни была; false - нигде не подключен
+pub async fn kick(hub_state: &HubState, user_id: UserId, reason: &str) -> bool {
+    if hub_state.kick(user_id, reason).await {
+        return true;
+    }
+    let Some(c) = get() else { return false };
+    let Some((node, _, _)) = c.remote(user_id).await else { return false };
+    match notify(&c.pool, &channel(&node), &Message::Kick { user_id, reason: reason.into() }).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            tracing::warn!("cluster: kick on {} failed: {:?}", node, e);
+            false
+        }
+    }
+}
+
+// из отдельного процесса (CLI): своего Cluster нет, узел берем из cluster_sessions
+pub async fn kick_remote(pool: &PgPool, user_id: UserId, reason: &str) -> Result<bool, sqlx::Error> {
+    let node: Option<(String,)> = sqlx::query_as("SELECT node_id FROM cluster_sessions WHERE user_id = $1")
+        .bind(user_id).fetch_optional(pool).await?;
+    let Some((node,)) = node else { return Ok(false) };
+    notify(pool, &channel(&node), &Message::Kick { user_id, reason: reason.into() }).await
+}
+
+// онлайн на любом узле (с теми же ключами)
+pub async fn is_online(hub_state: &HubState, user_id: UserId, x: &[u8; 32], ed: &[u8; 32]) -> bool {
+    if hub_state.is_online(user_id, x, ed) {
+        return true;
+    }
+    match get() {
+        Some(c) => c.remote(user_id).await.is_some_and(|(_, rx, red)| &rx == x && &red == ed),
+        None => false,
+    }
+}
+
+pub async fn public_x(hub_state: &HubState, user_id: UserId) -> Option<[u8; 32]> {
+    if let Some(x) = hub_state.public_x(user_id) {
+        return Some(x);
+    }
+    get()?.remote(user_id).await.map(|(_, x, _)| x)
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn message_codec() {
+        let msgs = [
+            Message::Deliver { to: 5, data: "AAEC".into() },
+            Message::Evict { user_id: 5, token: Some(42) },
+            Message::Kick { user_id: 5, reason: "bye".into() },
+            Message::Data { from: "n1".into(), device_id: 7, msg: json!({ "action": "data", "payload": { "t": 1.5 } }) },
+        ];
+        for m in msgs {
+            assert_eq!(Message::decode(&m.encode().unwrap()), Some(m));
+        }
+
+        // формат на проводе - как у узлов старой версии
+        let wire: Value = serde_json::from_str(&Message::Evict { user_id: 5, token: None }.encode().unwrap()).unwrap();
+        assert_eq!(wire, json!({ "kind": "evict", "user_id": 5 }));
+        assert_eq!(Message::decode(r#"{"kind":"evict","user_id":5}"#), Some(Message::Evict { user_id: 5, token: None }));
+        assert_eq!(Message::decode(r#"{"kind":"kick","user_id":3}"#), Some(Message::Kick { user_id: 3, reason: "kicked".into() }));
+        assert_eq!(Message::decode(r#"{"kind":"nope"}"#), None);
+        assert_eq!(Message::decode(r#"{"kind":"deliver","to":"x"}"#), None);
+    }
+
+    #[test]
+    fn notify_size_cap() {
+        let envelope = Message::Deliver { to: 1, data: String::new() }.encode().unwrap().len();
+        let fits = Message::Deliver { to: 1, data: "A".repeat(MAX_NOTIFY - envelope) };
+        assert_eq!(fits.encode().map(|p| p.len()), Some(MAX_NOTIFY));
+        let over = Message::Deliver { to: 1, data: "A".repeat(MAX_NOTIFY - envelope + 1) };
+        assert_eq!(over.encode(), None);
+        let data = Message::Data { from: "n1".into(), device_id: 1, msg: json!({ "payload": "x".repeat(MAX_NOTIFY) }) };
+        assert_eq!(data.encode(), None);
+    }
+}
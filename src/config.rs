@@ -1,18 +1,21 @@
-use std::{path::Path, sync::LazyLock};
+use std::{path::Path, sync::{Arc, LazyLock, Mutex, RwLock}};
 use config::FileFormat;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 // use serde_with::serde_as;
 use serde_with::{serde_as, StringWithSeparator};
 use serde_with::formats::CommaSeparator;
 use crate::hub::UserId;
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     // ==== SERVER ====
     pub bind_port: u16,
     pub bind_host: String,
     pub loglevel: String,
+    // "compact" - для человека, "json" - для Loki / Elasticsearch
+    pub log_format: String,
     // === WS ===
     pub heartbeat_timeout: u64,
     pub ping_timeout: u64,
@@ -25,6 +28,19 @@ pub struct Config {
     pub admins: Vec<UserId>,
     // === POSTGRESS ===
     pub postgres: String,
+    pub db_pool_min: u32,
+    pub db_pool_max: u32,
+    pub db_acquire_timeout_sec: u64,
+
+    // === HTTP: "*" = любой origin, иначе список "https://host[:port]" через запятую ===
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    pub cors_origins: Vec<String>,
+    // Cache-Control для статики: 0 = no-cache
+    pub static_cache_sec: u32,
+
+    // === протокол: допустимое расхождение часов клиента (nonce = unixtime), сколько ждать логин по email ===
+    pub nonce_skew_sec: u64,
+    pub login_timeout_sec: u64,
 
     // === site ===
     pub site_dir: String,
@@ -34,10 +50,97 @@ pub struct Config {
     pub seed_ed: String,
 
     pub email_code_expired_sec: u32,
+
+    // === MQTT (0 = выключен) ===
+    pub mqtt_port: u16,
+
+    // === raw TCP (0 = выключен) ===
+    pub tcp_port: u16,
+
+    // === UDP (0 = выключен), лимит датаграмм с одного IP ===
+    pub udp_port: u16,
+    pub udp_rate_per_sec: f64,
+    pub udp_burst: f64,
+
+    // === TLS (0 = выключен): HTTPS/WSS на отдельном порту, сертификат перечитывается по SIGHUP и при изменении файлов ===
+    pub tls_port: u16,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_reload_sec: u64,
+
+    // === размеры и количество соединений (0 = без ограничения) ===
+    pub max_frame_size: usize,
+    pub max_relay_size: usize,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub max_pending_logins: usize,
+
+    // === флуд: token bucket на соединение (в секунду / запас), аккаунт = соединение * rate_account_factor ===
+    pub rate_relay_per_sec: f64,
+    pub rate_relay_burst: f64,
+    pub rate_command_per_sec: f64,
+    pub rate_command_burst: f64,
+    pub rate_telemetry_per_sec: f64,
+    pub rate_telemetry_burst: f64,
+    pub rate_account_factor: f64,
+    // нарушений за минуту до отключения и бана
+    pub rate_violations_max: u32,
+    pub ban_sec: u64,
+    pub ban_ip: bool,
+
+    // === audit_log: сколько дней хранить (0 = всегда) ===
+    pub audit_retention_days: u32,
+
+    // === кластер: несколько узлов на одной БД (node_id пустой = случайный) ===
+    pub cluster: bool,
+    pub node_id: String,
+
+    // === остановка: сколько ждать незаконченные обработчики, через сколько клиентам переподключаться ===
+    pub shutdown_timeout: u64,
+    pub reconnect_after_sec: u64,
+
+    // === webhooks ===
+    pub webhook_retries: u32,
+    pub webhook_retry_base_sec: u64,
+    pub webhook_log_days: u32, // webhook_deliveries: сколько дней хранить (0 = всегда)
     // pub max_size: Option<usize>,
 }
 
-pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
+// поля, которые можно менять без рестарта (SIGHUP или {"action":"reload_config"}); остальные - только из CONFIG
+#[derive(Debug, Clone, PartialEq)]
+pub struct Runtime {
+    pub admins: Vec<UserId>,
+    pub loglevel: String,
+    pub heartbeat_timeout: u64,
+    pub ping_timeout: u64,
+}
+
+const RELOADABLE: [&str; 4] = ["admins", "loglevel", "heartbeat_timeout", "ping_timeout"];
+
+impl Runtime {
+    fn changed(&self, next: &Runtime) -> Vec<&'static str> {
+        let flags = [
+            self.admins != next.admins,
+            self.loglevel != next.loglevel,
+            self.heartbeat_timeout != next.heartbeat_timeout,
+            self.ping_timeout != next.ping_timeout,
+        ];
+        RELOADABLE.iter().zip(flags).filter(|(_, f)| *f).map(|(k, _)| *k).collect()
+    }
+}
+
+impl From<&Config> for Runtime {
+    fn from(c: &Config) -> Self {
+        Runtime {
+            admins: c.admins.clone(),
+            loglevel: c.loglevel.clone(),
+            heartbeat_timeout: c.heartbeat_timeout,
+            ping_timeout: c.ping_timeout,
+        }
+    }
+}
+
+fn load() -> Result<Config, Vec<String>> {
     const DEFAULTS: &str = std::include_str!("config/default.toml");
 
     let mut builder =
@@ -52,13 +155,207 @@ pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     let settings = builder
         .add_source(config::Environment::with_prefix("AG"))
         .build()
-        .and_then(|c| c.try_deserialize::<Config>());
+        .and_then(|c| c.try_deserialize::<Config>())
+        .map_err(|e| vec![e.to_string()])?;
+    settings.validate()?;
+    Ok(settings)
+}
 
-    match settings {
-        Ok(settings) => settings,
-        Err(error) => {
-            eprintln!("configuration error: {}", error);
-            std::process::exit(1);
+pub static CONFIG: LazyLock<Config> = LazyLock::new(|| match load() {
+    Ok(settings) => settings,
+    Err(errors) => {
+        eprintln!("configuration error:");
+        for e in errors {
+            eprintln!("  {}", e);
         }
+        std::process::exit(1);
     }
 });
+
+// читатели берут снимок целиком: Arc клонируется под коротким read-lock, дальше без блокировок
+static RUNTIME: LazyLock<RwLock<Arc<Runtime>>> = LazyLock::new(|| RwLock::new(Arc::new(Runtime::from(&*CONFIG))));
+
+pub fn runtime() -> Arc<Runtime> {
+    RUNTIME.read().unwrap().clone()
+}
+
+#[derive(Default)]
+struct ReloadStatus {
+    count: u64,
+    last_at: u64,
+    last_error: Option<String>,
+    restart_required: Vec<String>,
+}
+
+static RELOAD_STATUS: LazyLock<Mutex<ReloadStatus>> = LazyLock::new(Default::default);
+
+// для /status
+pub fn reload_status() -> Value {
+    let s = RELOAD_STATUS.lock().unwrap();
+    json!({
+        "reloads": s.count,
+        "last_reload": s.last_at,
+        "last_error": s.last_error,
+        "restart_required": s.restart_required,
+    })
+}
+
+// перечитать etc/config.toml + env; применяются только RELOADABLE, про остальные изменения - предупреждение
+pub fn reload() -> Result<Value, String> {
+    let result = load().map_err(|e| e.join("; "));
+    let mut status = RELOAD_STATUS.lock().unwrap();
+    status.last_at = crate::crypto25519::get_unixtime();
+
+    let fresh = match result {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("Config reload failed, keeping current: {}", e);
+            status.last_error = Some(e.clone());
+            return Err(e);
+        }
+    };
+
+    let restart_required = restart_required(&CONFIG, &fresh);
+    let next = Runtime::from(&fresh);
+    let prev = std::mem::replace(&mut *RUNTIME.write().unwrap(), Arc::new(next.clone()));
+    let changed = prev.changed(&next);
+
+    // лог до смены уровня, иначе при переходе на WARN запись о reload потеряется
+    tracing::info!("Config reloaded: changed {:?}", changed);
+    if !restart_required.is_empty() {
+        tracing::warn!("Config reload: {:?} changed but need a restart", restart_required);
+    }
+    if prev.loglevel != next.loglevel {
+        crate::set_loglevel(&next.loglevel);
+    }
+    status.count += 1;
+    status.last_error = None;
+    status.restart_required = restart_required.clone();
+    Ok(json!({ "changed": changed, "restart_required": restart_required }))
+}
+
+// поля вне RELOADABLE, которые в файле уже другие, чем у запущенного сервера
+fn restart_required(current: &Config, fresh: &Config) -> Vec<String> {
+    let (Ok(Value::Object(a)), Ok(Value::Object(b))) = (serde_json::to_value(current), serde_json::to_value(fresh)) else {
+        return vec![];
+    };
+    b.iter()
+        .filter(|(k, v)| !RELOADABLE.contains(&k.as_str()) && a.get(*k) != Some(*v))
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+pub fn watch() {
+    #[cfg(unix)]
+    tokio::spawn(async {
+        let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("SIGHUP handler");
+        while hup.recv().await.is_some() {
+            tracing::info!("SIGHUP: reloading config");
+            let _ = reload();
+        }
+    });
+}
+
+impl Config {
+    // все ошибки сразу, по одной строке на поле
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, msg: String| if !ok { errors.push(msg) };
+
+        check(self.bind_host.parse::<std::net::IpAddr>().is_ok(), format!("bind_host: '{}' is not an IP address", self.bind_host));
+        check(matches!(self.loglevel.as_str(), "TRACE" | "DEBUG" | "INFO" | "WARN" | "ERROR"),
+            format!("loglevel: '{}' is not one of TRACE, DEBUG, INFO, WARN, ERROR", self.loglevel));
+        check(matches!(self.log_format.as_str(), "compact" | "json"),
+            format!("log_format: '{}' is not one of compact, json", self.log_format));
+        check(self.ping_timeout < self.heartbeat_timeout,
+            format!("ping_timeout ({}) must be less than heartbeat_timeout ({})", self.ping_timeout, self.heartbeat_timeout));
+
+        check(self.db_pool_max >= 1, "db_pool_max must be at least 1".into());
+        check(self.db_pool_min <= self.db_pool_max,
+            format!("db_pool_min ({}) must not exceed db_pool_max ({})", self.db_pool_min, self.db_pool_max));
+        check(self.db_acquire_timeout_sec >= 1, "db_acquire_timeout_sec must be at least 1".into());
+
+        check(!self.cors_origins.is_empty(), "cors_origins: empty, use \"*\" to allow any origin".into());
+        if self.cors_origins.len() > 1 {
+            check(!self.cors_origins.iter().any(|o| o == "*"), "cors_origins: \"*\" cannot be combined with other origins".into());
+        }
+        for o in self.cors_origins.iter().filter(|o| *o != "*") {
+            check(is_origin(o), format!("cors_origins: '{}' is not an origin (expected http(s)://host[:port], no path)", o));
+        }
+
+        check((1..=300).contains(&self.nonce_skew_sec), format!("nonce_skew_sec ({}) must be in 1..=300", self.nonce_skew_sec));
+        check(self.login_timeout_sec >= 1, "login_timeout_sec must be at least 1".into());
+        check(self.login_timeout_sec <= self.email_code_expired_sec as u64,
+            format!("login_timeout_sec ({}) must not exceed email_code_expired_sec ({})", self.login_timeout_sec, self.email_code_expired_sec));
+
+        for (name, seed) in [("seed_x", &self.seed_x), ("seed_ed", &self.seed_ed)] {
+            // пустой - main подскажет сгенерированный
+            check(seed.is_empty() || (seed.len() == 64 && seed.chars().all(|c| c.is_ascii_hexdigit())),
+                format!("{}: expected 64 hex characters", name));
+        }
+
+        check(self.bind_port != 0 || self.tls_port != 0, "bind_port and tls_port are both 0: nothing to listen on".into());
+        if self.tls_port != 0 {
+            for (name, path) in [("tls_cert", &self.tls_cert), ("tls_key", &self.tls_key)] {
+                check(Path::new(path).is_file(), format!("{}: file '{}' not found (tls_port is {})", name, path, self.tls_port));
+            }
+        }
+
+        for (name, rate, burst) in [
+            ("udp", self.udp_rate_per_sec, self.udp_burst),
+            ("rate_relay", self.rate_relay_per_sec, self.rate_relay_burst),
+            ("rate_command", self.rate_command_per_sec, self.rate_command_burst),
+            ("rate_telemetry", self.rate_telemetry_per_sec, self.rate_telemetry_burst),
+        ] {
+            check(rate > 0.0 && burst >= 1.0, format!("{}: rate must be > 0 and burst >= 1 (got {} / {})", name, rate, burst));
+        }
+        check(self.rate_account_factor >= 1.0, format!("rate_account_factor ({}) must be at least 1", self.rate_account_factor));
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+fn is_origin(s: &str) -> bool {
+    match url::Url::parse(s) {
+        Ok(u) => matches!(u.scheme(), "http" | "https") && u.host().is_some()
+            && u.path() == "/" && !s.ends_with('/') && u.query().is_none() && u.fragment().is_none(),
+        Err(_) => false,
+    }
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Config {
+        config::Config::builder()
+            .add_source(config::File::from_str(std::include_str!("config/default.toml"), FileFormat::Toml))
+            .build().unwrap()
+            .try_deserialize().unwrap()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(defaults().validate(), Ok(()));
+    }
+
+    #[test]
+    fn precise_errors() {
+        let mut c = defaults();
+        c.db_pool_min = 10;
+        c.db_pool_max = 5;
+        c.cors_origins = vec!["https://app.example.com".into(), "example.com".into(), "https://x.io/path".into()];
+        c.nonce_skew_sec = 0;
+        c.bind_port = 0;
+        let errors = c.validate().unwrap_err();
+        assert_eq!(errors, vec![
+            "db_pool_min (10) must not exceed db_pool_max (5)".to_string(),
+            "cors_origins: 'example.com' is not an origin (expected http(s)://host[:port], no path)".to_string(),
+            "cors_origins: 'https://x.io/path' is not an origin (expected http(s)://host[:port], no path)".to_string(),
+            "nonce_skew_sec (0) must be in 1..=300".to_string(),
+            "bind_port and tls_port are both 0: nothing to listen on".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,116 @@
+// Сколько соединений держим: всего, с одного IP и сколько из них висят в логине (email код).
+// Лимит 0 = без ограничения. Счетчики отпускаются в Drop, так что abort/evict тоже освобождают место.
+
+use actix_ws::CloseCode;
+use dashmap::DashMap;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::config::CONFIG;
+
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static PENDING_LOGINS: AtomicUsize = AtomicUsize::new(0);
+static PER_IP: LazyLock<DashMap<String, usize>> = LazyLock::new(DashMap::new);
+
+#[derive(Debug, PartialEq)]
+pub enum Reject {
+    Total,
+    PerIp,
+    PendingLogins,
+}
+
+impl Reject {
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            Reject::Total | Reject::PendingLogins => CloseCode::Again, // 1013, попробовать позже
+            Reject::PerIp => CloseCode::Policy,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Reject::Total => "server is full",
+            Reject::PerIp => "too many connections from this IP",
+            Reject::PendingLogins => "too many pending logins",
+        }
+    }
+}
+
+fn acquire(counter: &AtomicUsize, max: usize) -> bool {
+    if counter.fetch_add(1, Ordering::SeqCst) >= max && max != 0 {
+        counter.fetch_sub(1, Ordering::SeqCst);
+        return false;
+    }
+    true
+}
+
+pub struct ConnGuard {
+    ip: String,
+}
+
+pub fn connect(ip: &str) -> Result<ConnGuard, Reject> {
+    if !acquire(&TOTAL, CONFIG.max_connections) {
+        return Err(Reject::Total);
+    }
+    let max = CONFIG.max_connections_per_ip;
+    let mut n = PER_IP.entry(ip.to_string()).or_insert(0);
+    if max != 0 && *n >= max {
+        drop(n);
+        TOTAL.fetch_sub(1, Ordering::SeqCst);
+        return Err(Reject::PerIp);
+    }
+    *n += 1;
+    Ok(ConnGuard { ip: ip.to_string() })
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        TOTAL.fetch_sub(1, Ordering::SeqCst);
+        PER_IP.remove_if_mut(&self.ip, |_, n| {
+            *n -= 1;
+            *n == 0
+        });
+    }
+}
+
+pub struct LoginGuard(());
+
+pub fn login() -> Result<LoginGuard, Reject> {
+    if !acquire(&PENDING_LOGINS, CONFIG.max_pending_logins) {
+        return Err(Reject::PendingLogins);
+    }
+    Ok(LoginGuard(()))
+}
+
+impl Drop for LoginGuard {
+    fn drop(&mut self) {
+        PENDING_LOGINS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn connections() -> usize {
+    TOTAL.load(Ordering::Relaxed)
+}
+
+pub fn pending_logins() -> usize {
+    PENDING_LOGINS.load(Ordering::Relaxed)
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_ip_cap_and_release() {
+        let max = CONFIG.max_connections_per_ip;
+        let mut held: Vec<ConnGuard> = (0..max).map(|_| connect("192.0.2.1").unwrap()).collect();
+        assert_eq!(connect("192.0.2.1").err(), Some(Reject::PerIp));
+        assert!(connect("192.0.2.2").is_ok()); // другой IP не задет
+
+        held.pop();
+        held.push(connect("192.0.2.1").unwrap());
+        drop(held);
+        assert!(!PER_IP.contains_key("192.0.2.1"));
+    }
+}
@@ -28,7 +28,7 @@ pub fn base64_u8_32(s: &str) -> Result<[u8; 32], base64::DecodeError> {
 //     sk.sign(data).to_bytes()
 // }
 pub fn verify(data: &[u8], sig: &[u8; 64], pk: &VerifyingKey) -> bool {
-    pk.verify(data, &Signature::from_bytes(&sig)).is_ok()
+    pk.verify(data, &Signature::from_bytes(sig)).is_ok()
 }
 // pub fn x25519_shared_key(my_secret: [u8; 32], their_public: [u8; 32]) -> [u8; 32] {
 //     x25519(my_secret, their_public)
@@ -45,7 +45,7 @@ pub fn nonce_from_u64(n: u64) -> XNonce {
     out[0..8].copy_from_slice(&b);
     out[8..16].copy_from_slice(&b);
     out[16..24].copy_from_slice(&b);
-    XNonce::from_slice(&out).clone()
+    *XNonce::from_slice(&out)
 }
 
 pub fn encrypt_message(
@@ -86,7 +86,7 @@ pub fn seed() -> [u8; 32] {
 }
 
 pub fn x25519_secret(seed: &[u8; 32]) -> [u8; 32] {
-    let mut sk = seed.clone();
+    let mut sk = *seed;
     sk[0]  &= 248;
     sk[31] &= 127;
     sk[31] |= 64;
@@ -125,6 +125,7 @@ pub fn encrypt_and_sign(
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DecryptError {
     BadNonce,
     BadSignature,
@@ -146,7 +147,7 @@ pub fn verify_and_decrypt(
     let mut sig_arr = [0u8; 64];
     sig_arr.copy_from_slice(sig_bytes);
 
-    if !ed_he_public.verify(nonce_and_cipher, &Signature::from_bytes(&sig_arr)).is_ok() { return Err(DecryptError::BadSignature); }
+    if ed_he_public.verify(nonce_and_cipher, &Signature::from_bytes(&sig_arr)).is_err() { return Err(DecryptError::BadSignature); }
    
     let mut nonce_arr = [0u8; 8];
     nonce_arr.copy_from_slice(&nonce_and_cipher[0..8]);
@@ -168,6 +169,7 @@ pub fn verify_and_decrypt(
 // ==================================================
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow, clippy::bool_assert_comparison)]
 mod tests {
     use hex::FromHex;
     use super::*; 
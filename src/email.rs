@@ -1,4 +1,5 @@
 use crate::config::CONFIG;
+use crate::metrics;
 
 use lettre::{
         message::Message,
@@ -7,11 +8,34 @@ use lettre::{
         message::{header::ContentType}
 };
 
+// для подстановки пользовательских строк (имена, пути) в html письма
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
 pub async fn send_email(
     to: &str,
     subject: &str,
     html_body: &str
 ) -> anyhow::Result<()> {
+    let _in_flight = crate::shutdown::in_flight();
+    let result = send(to, subject, html_body);
+    metrics::inc(if result.is_ok() { &metrics::EMAILS_SENT } else { &metrics::EMAILS_FAILED });
+    result
+}
+
+fn send(to: &str, subject: &str, html_body: &str) -> anyhow::Result<()> {
     let email = Message::builder()
         .from(CONFIG.smtp2go_from.parse()?)
         .to(to.parse()?)
@@ -0,0 +1,208 @@
+// POST /api/v1/packet - один зашифрованный запрос без WebSocket (скрипты, cron, serverless)
+//
+// тело запроса: [public_ed 32][nonce|ciphertext|sig]   (внутри [msg_id u16 LE][cmd u8][body], cmd 0x00 или 0x10)
+// тело ответа:  [0u32][encrypt_and_sign([msg_id][0x01][body])] - как ответ по WS
+// 400 "timestamp_error:<unixtime>" - часы клиента ушли, 401 - неизвестный ключ или подпись
+// 403 - бан за флуд, 429 "rate_limited:<kind>" - лимит аккаунта (соединения тут нет, считается только он)
+
+use actix_web::{HttpRequest, HttpResponse, web};
+use ed25519_dalek::VerifyingKey;
+use std::sync::{Arc, LazyLock, Mutex};
+use crate::{
+    MY_CONFIG, config::CONFIG, crypto25519::{self, DecryptError},
+    hub::HubState,
+    ratelimit::{self, Kind},
+    server::{server, server_frame},
+    udp::ReplayCache,
+};
+
+static REPLAY: LazyLock<Mutex<ReplayCache>> = LazyLock::new(|| Mutex::new(ReplayCache::default()));
+
+pub async fn packet(
+    req: HttpRequest,
+    body: web::Bytes,
+    hub_state: web::Data<Arc<HubState>>,
+    db: web::Data<sqlx::PgPool>,
+) -> HttpResponse {
+    if crate::shutdown::is_shutting_down() {
+        return HttpResponse::ServiceUnavailable().body(crate::shutdown::reason());
+    }
+    if body.len() < 32 + 8 + 64 {
+        return HttpResponse::BadRequest().body("Packet too short");
+    }
+    let (public_ed_bytes, blob) = body.split_at(32);
+
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    if ratelimit::is_banned(db.get_ref(), public_ed_bytes, &ip).await {
+        return HttpResponse::Forbidden().body("Banned");
+    }
+
+    let row: Option<crate::accounts::LoginRow> = match sqlx::query_as(&crate::accounts::LOGIN_SQL)
+        .bind(public_ed_bytes)
+        .fetch_optional(db.get_ref())
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!("Database error in /api/v1/packet: {:?}", e);
+            return HttpResponse::InternalServerError().body("DB error");
+        }
+    };
+    let Some((id, public_x, status, status_reason)) = row else {
+        return HttpResponse::Unauthorized().body("Unknown public_ed");
+    };
+    if let Some(reason) = crate::accounts::denied(status, status_reason) {
+        return HttpResponse::Forbidden().body(reason);
+    }
+    let public_x: [u8; 32] = public_x.try_into().unwrap_or([0u8; 32]);
+    let Ok(public_ed) = VerifyingKey::from_bytes(public_ed_bytes.try_into().unwrap()) else {
+        return HttpResponse::BadRequest().body("Invalid public_ed");
+    };
+
+    let bin = match crypto25519::verify_and_decrypt(blob, &MY_CONFIG.secret_x, &public_x, &public_ed, CONFIG.nonce_skew_sec)
+        .inspect_err(crate::metrics::decrypt_failed)
+    {
+        Ok(v) if v.len() >= 3 => v,
+        Err(DecryptError::BadNonce) => {
+            return HttpResponse::BadRequest().body(format!("timestamp_error:{}", crypto25519::get_unixtime()));
+        }
+        Err(DecryptError::BadSignature) => return HttpResponse::Unauthorized().body("Signature failed"),
+        _ => return HttpResponse::BadRequest().body("Bad format"),
+    };
+
+    // тот же пакет второй раз не выполняем
+    let nonce = u64::from_le_bytes(blob[0..8].try_into().unwrap());
+    let sig: [u8; 64] = blob[blob.len() - 64..].try_into().unwrap();
+    if !REPLAY.lock().unwrap().fresh(id, nonce, sig, crypto25519::get_unixtime()) {
+        tracing::warn!("HTTP packet replay from {}", id);
+        return HttpResponse::Conflict().body("Replay");
+    }
+
+    let message_id = u16::from_le_bytes([bin[0], bin[1]]);
+    let cmd = bin[2];
+    tracing::info!("HTTP packet from {}: cmd={:#04x}", id, cmd);
+    let kind = if cmd == 0x10 { Kind::Telemetry } else { Kind::Command };
+    if !ratelimit::account_check(id, kind) {
+        crate::metrics::inc(&crate::metrics::RATE_LIMITED);
+        return HttpResponse::TooManyRequests().body(format!("rate_limited:{}", kind.as_str()));
+    }
+    let reply = if cmd == 0x00 || cmd == 0x10 {
+        server(cmd, id, &bin[3..], db.get_ref(), hub_state.get_ref()).await
+    } else {
+        serde_json::to_vec(&serde_json::json!({ "error": "Invalid cmd" })).unwrap()
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(server_frame(message_id, 0x01, &reply, &public_x))
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, http::StatusCode, test};
+    use ed25519_dalek::{Signer, SigningKey};
+    use sqlx::PgPool;
+    use crate::crypto25519::{encrypt_message, ed25519_secret, get_unixtime, seed, x25519_public, x25519_secret};
+
+    async fn call(pool: &PgPool, body: Vec<u8>) -> (StatusCode, String) {
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(Arc::new(HubState::default())))
+            .app_data(web::Data::new(pool.clone()))
+            .route("/api/v1/packet", web::post().to(packet))).await;
+        let req = test::TestRequest::post().uri("/api/v1/packet")
+            .peer_addr("127.0.0.9:5000".parse().unwrap())
+            .set_payload(body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let status = res.status();
+        (status, String::from_utf8_lossy(&test::read_body(res).await).into_owned())
+    }
+
+    struct Device {
+        x: [u8; 32],
+        ed: SigningKey,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            let s = seed();
+            Device { x: x25519_secret(&s), ed: ed25519_secret(&s) }
+        }
+
+        // [public_ed][nonce|ciphertext|sig], nonce задается - для проверки часов
+        fn packet(&self, nonce: u64, msg_id: u16, cmd: u8, body: &[u8]) -> Vec<u8> {
+            let mut inner = msg_id.to_le_bytes().to_vec();
+            inner.push(cmd);
+            inner.extend_from_slice(body);
+            let mut blob = nonce.to_le_bytes().to_vec();
+            blob.extend_from_slice(&encrypt_message(&MY_CONFIG.public_x, &self.x, &inner, &nonce));
+            let sig = self.ed.sign(&blob).to_bytes();
+            blob.extend_from_slice(&sig);
+            let mut out = self.ed.verifying_key().to_bytes().to_vec();
+            out.extend_from_slice(&blob);
+            out
+        }
+    }
+
+    #[actix_web::test]
+    async fn short_packet_400() {
+        let pool = sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://nobody@127.0.0.1:1/none").unwrap();
+        let (status, body) = call(&pool, vec![0u8; 50]).await;
+        assert_eq!((status, body.as_str()), (StatusCode::BAD_REQUEST, "Packet too short"));
+    }
+
+    // AG_POSTGRES=postgres://... cargo test packet_status -- --ignored
+    #[actix_web::test]
+    #[ignore]
+    async fn packet_status_codes() {
+        let pool = PgPool::connect(&std::env::var("AG_POSTGRES").unwrap()).await.unwrap();
+        crate::MIGRATOR.run(&pool).await.unwrap();
+
+        let dev = Device::new();
+        let ed = dev.ed.verifying_key().to_bytes();
+        let id: i32 = sqlx::query_scalar("INSERT INTO users (public_x, public_ed) VALUES ($1, $2) RETURNING id")
+            .bind(&x25519_public(&dev.x)[..]).bind(&ed[..])
+            .fetch_one(&pool).await.unwrap();
+        let now = get_unixtime();
+
+        // 401: чужой ключ, испорченная подпись
+        assert_eq!(call(&pool, Device::new().packet(now, 1, 0x55, b"")).await.0, StatusCode::UNAUTHORIZED);
+        let mut bad = dev.packet(now, 1, 0x55, b"");
+        *bad.last_mut().unwrap() ^= 1;
+        assert_eq!(call(&pool, bad).await, (StatusCode::UNAUTHORIZED, "Signature failed".into()));
+
+        // 400: часы ушли
+        let (status, body) = call(&pool, dev.packet(now - 1000, 1, 0x55, b"")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.starts_with("timestamp_error:"), "{}", body);
+
+        // 200, повтор того же пакета - 409
+        let ok = dev.packet(now, 1, 0x55, b"");
+        assert_eq!(call(&pool, ok.clone()).await.0, StatusCode::OK);
+        assert_eq!(call(&pool, ok).await.0, StatusCode::CONFLICT);
+
+        // 429: лимит аккаунта на команды
+        let mut limited = None;
+        for msg_id in 2..1000 {
+            let (status, body) = call(&pool, dev.packet(now, msg_id, 0x55, b"")).await;
+            if status != StatusCode::OK {
+                limited = Some((status, body));
+                break;
+            }
+        }
+        assert_eq!(limited, Some((StatusCode::TOO_MANY_REQUESTS, "rate_limited:command".into())));
+
+        // 403: заблокирован, забанен
+        sqlx::query("UPDATE users SET status = 'suspended', status_reason = 'test' WHERE id = $1").bind(id).execute(&pool).await.unwrap();
+        assert_eq!(call(&pool, dev.packet(now, 1000, 0x55, b"")).await, (StatusCode::FORBIDDEN, "suspended: test".into()));
+        sqlx::query("INSERT INTO bans (user_id, public_ed, reason, until) VALUES ($1, $2, 'test', now() + interval '1 minute')")
+            .bind(id).bind(&ed[..]).execute(&pool).await.unwrap();
+        assert_eq!(call(&pool, dev.packet(now, 1001, 0x55, b"")).await, (StatusCode::FORBIDDEN, "Banned".into()));
+
+        sqlx::query("DELETE FROM bans WHERE public_ed = $1").bind(&ed[..]).execute(&pool).await.unwrap();
+        sqlx::query("DELETE FROM users WHERE id = $1").bind(id).execute(&pool).await.unwrap();
+    }
+}
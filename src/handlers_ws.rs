@@ -1,4 +1,3 @@
-use actix_ws;
 use ed25519_dalek::{VerifyingKey};
 use futures_util::StreamExt;
 use futures::future::{AbortHandle, Abortable};
@@ -6,13 +5,14 @@ use actix_web::{Error, HttpRequest, HttpResponse, web, error::ErrorBadRequest};
 use serde_json::{json};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use hex::FromHex;
 use tokio::time::{timeout, Duration};
+use tracing::Instrument;
 use crate::{
-    MY_CONFIG, config::CONFIG, crypto25519::{self, DecryptError}, email::send_email,
-    hub::{self, HubState, UserId, send_to},
-    server::server,
+    MY_CONFIG, accounts, audit, config::CONFIG, connlimit, crypto25519, email::send_email,
+    hub::{self, HubState},
+    ratelimit::{self, ConnLimits},
+    server::packet,
 };
 use sqlx::Row;
 
@@ -55,25 +55,63 @@ async fn verify_signature(
     false
 }
 
+// логины, провалы подписи, смена ключей -> audit_log
+fn audit_login(pool: &sqlx::PgPool, actor: Option<i32>, action: &str, ip: &str, result: &'static str, details: serde_json::Value) {
+    audit::write(pool, audit::Entry { actor, target: None, action: action.into(), ip: Some(ip.to_string()), result, details });
+}
+
 pub async fn handler(
     req: HttpRequest,
     payload: web::Payload,
-    hub_state: web::Data<Arc<RwLock<HubState>>>,
+    hub_state: web::Data<Arc<HubState>>,
     db: web::Data<sqlx::PgPool>,
     path: web::Path<String>,  // <-- kind, public_ed
 ) -> Result<HttpResponse, Error> {
 
-    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, payload)?;
+    if crate::shutdown::is_shutting_down() {
+        return Ok(HttpResponse::ServiceUnavailable().body(crate::shutdown::reason()));
+    }
+
     let is_user = req.path().starts_with("/ws/user/");
     let public_ed_bytes: [u8; 32] = <[u8; 32]>::from_hex(path.into_inner()).map_err(|_| ErrorBadRequest("Invalid public_ed"))?;
     let public_ed = VerifyingKey::from_bytes(&public_ed_bytes).map_err(|_| ErrorBadRequest("Invalid public_ed"))?;
     let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+
+    // сначала лимиты в памяти, запрос бана в БД - только если они пропустили
+    let conn_guard = connlimit::connect(&ip);
+    if conn_guard.is_ok() && ratelimit::is_banned(db.get_ref(), &public_ed_bytes, &ip).await {
+        tracing::warn!("Banned public_ed or IP {}, reject", ip);
+        // забаненный реконнектится в цикле: одна запись за бан (по IP, если банится IP)
+        let key = format!("banned:{}", if CONFIG.ban_ip { ip.clone() } else { hex::encode_upper(public_ed_bytes) });
+        audit::write_coalesced(db.get_ref(), key, Duration::from_secs(CONFIG.ban_sec), audit::Entry {
+            action: "login".into(), ip: Some(ip.clone()), result: audit::DENIED,
+            details: json!({ "reason": "banned", "public_ed": hex::encode_upper(public_ed_bytes) }), ..Default::default()
+        });
+        return Ok(HttpResponse::Forbidden().body("Banned"));
+    }
+
+    let (response, mut session, msg_stream) = actix_ws::handle(&req, payload)?;
+    let max_frame = if CONFIG.max_frame_size == 0 { usize::MAX } else { CONFIG.max_frame_size };
+    let mut msg_stream = msg_stream.max_frame_size(max_frame);
+
+    // лимиты отдаем close-кодом уже после upgrade, чтобы клиент видел причину
+    let conn_guard = match conn_guard {
+        Ok(g) => g,
+        Err(r) => {
+            tracing::warn!("WS from {} rejected: {}", ip, r.reason());
+            hub::Conn::Ws(session).close_with(r.close_code(), r.reason()).await;
+            return Ok(response);
+        }
+    };
     let pool = db.clone(); // &sqlx::PgPool get_ref();
     tracing::debug!("WebSocket connection from {}", ip);
 
+    // span на все соединение: логин и цикл сессии; user_id появляется после логина
+    let span = tracing::info_span!("ws", route = if is_user { "user" } else { "device" }, ip = %ip, user_id = tracing::field::Empty);
+
    // === ask BASE for login ===
 
-    let row = sqlx::query_as("SELECT id, public_x FROM users WHERE public_ed = $1")
+    let row: Option<accounts::LoginRow> = sqlx::query_as(&accounts::LOGIN_SQL)
         .bind(&public_ed_bytes[..])
         .fetch_optional(pool.get_ref())
         .await
@@ -83,27 +121,38 @@ pub async fn handler(
         })?;
 
     if row.is_none() {
-        println!("ROW NONE!");
+        tracing::debug!("Unknown public_ed, login");
 
         if !is_user {
             tracing::warn!("Unknown device, close");
             return Err(ErrorBadRequest("Unknown device"));
         }
 
+        let login_guard = match connlimit::login() {
+            Ok(g) => g,
+            Err(r) => {
+                tracing::warn!("WS login from {} rejected: {}", ip, r.reason());
+                hub::Conn::Ws(session).close_with(r.close_code(), r.reason()).await;
+                return Ok(response);
+            }
+        };
+
         let hash = crypto25519::seed();
-        let hash = hex::encode_upper(&hash);
+        let hash = hex::encode_upper(hash);
 
         let mut stage = 0;
         let mut mail: String = String::new();
         // let code: String = "332218".to_string(); //  format!("{:06}", rand::random::<u32>() % 1_000_000);
         let mut ses = session.clone();
         let mut ses_timeout = session.clone();
+        let ip = ip.clone();
 
         actix_web::rt::spawn(async move {
+            let _guards = (conn_guard, login_guard);
 
             let _ = ses.text(json!({"action": "login", "hash": hash}).to_string()).await;
 
-            let result = timeout(Duration::from_secs(CONFIG.email_code_expired_sec as u64), async {
+            let result = timeout(Duration::from_secs(CONFIG.login_timeout_sec), async {
 
                 let mut code_sent: String = String::new();
                 while let Some(Ok(msg)) = msg_stream.next().await {
@@ -127,6 +176,7 @@ pub async fn handler(
                                         }
 
                                         if !verify_signature(&mut ses,&format!("{}/email/{}", hash, email), &public_ed, &signature).await {
+                                            audit_login(pool.get_ref(), None, "signature_failed", &ip, audit::DENIED, json!({ "stage": "email", "email": email }));
                                             break;
                                         }
 
@@ -139,10 +189,7 @@ pub async fn handler(
                                             break;
                                         }
 
-                                        let (code, mail_needs) = {
-                                            let mut hub = hub_state.write().await;
-                                            hub.get_email_code(&email)
-                                        };
+                                        let (code, mail_needs) = hub_state.get_email_code(&email);
 
                                         code_sent = code.clone();
                                         if !mail_needs {
@@ -170,6 +217,7 @@ pub async fn handler(
                                             break;
                                         }
                                         if received_code != code_sent {
+                                            audit_login(pool.get_ref(), None, "login", &ip, audit::DENIED, json!({ "reason": "invalid code", "email": mail }));
                                             error_close(&mut ses, "Invalid code").await;
                                             break;
                                         }
@@ -177,26 +225,33 @@ pub async fn handler(
                                         if !verify_signature(&mut ses,
                                             &format!("{}/code/{}/{}",
                                             hash, received_code, x_public), &public_ed, &signature).await {
+                                            audit_login(pool.get_ref(), None, "signature_failed", &ip, audit::DENIED, json!({ "stage": "code", "email": mail }));
                                             break;
                                         }
 
                                         // save to db with email
                                         let public_x_bytes: [u8; 32] = <[u8; 32]>::from_hex(&x_public).map_err(|_| ErrorBadRequest("Invalid x_public")).unwrap();
 
-                                        let row = sqlx::query(
+                                        // заблокированный аккаунт новыми ключами не разблокировать: строки не будет
+                                        let row = sqlx::query(&format!(
 r"INSERT INTO users (email, public_x, public_ed) VALUES ($1, $2, $3) ON CONFLICT (email)
-DO UPDATE SET public_x = EXCLUDED.public_x, public_ed = EXCLUDED.public_ed
-RETURNING id"
+DO UPDATE SET public_x = EXCLUDED.public_x, public_ed = EXCLUDED.public_ed WHERE {}
+RETURNING id, (xmax <> 0) AS updated", accounts::ACTIVE_SQL)
                                         )
                                         .bind(&mail)
                                         .bind(&public_x_bytes[..])
                                         .bind(&public_ed_bytes[..])
-                                        .fetch_one(pool.get_ref())
+                                        .fetch_optional(pool.get_ref())
                                         .await;
 
 
                                         let row = match row {
-                                            Ok(r) => r,
+                                            Ok(Some(r)) => r,
+                                            Ok(None) => {
+                                                audit_login(pool.get_ref(), None, "key_change", &ip, audit::DENIED, json!({ "reason": "account not active", "email": mail }));
+                                                error_close(&mut ses, "account not active").await;
+                                                break;
+                                            }
                                             Err(e) => {
                                                 error_close(&mut ses, &format!("DB error: {:?}", e)).await;
                                                 break;
@@ -204,6 +259,9 @@ RETURNING id"
                                         };
 
                                         let id: i32 = row.get("id");
+                                        // email уже был - ключи заменены
+                                        let action = if row.get::<bool, _>("updated") { "key_change" } else { "register" };
+                                        audit_login(pool.get_ref(), Some(id), action, &ip, audit::OK, json!({ "email": mail }));
                                         let _ = ses.text(json!({
                                             "action": "login_success",
                                             "my_id": id,
@@ -226,42 +284,58 @@ RETURNING id"
                 error_close(&mut ses_timeout, "Timeout").await;
             }
 
-        });
+        }.instrument(span));
 
     // =================================================================================
 
-    } else {
+    } else if let Some((id, public_x, status, status_reason)) = row {
 
-        let (id, public_x): (i32, Vec<u8>) = row.unwrap();
         // public_ed is already known
+        if let Some(reason) = accounts::denied(status, status_reason) {
+            tracing::warn!(parent: &span, "WS login {} rejected: {}", id, reason);
+            audit_login(pool.get_ref(), Some(id), "login", &ip, audit::DENIED, json!({ "reason": reason }));
+            hub::Conn::Ws(session).close_with(actix_ws::CloseCode::Policy, &reason).await;
+            return Ok(response);
+        }
         let public_x: [u8; 32] = public_x.try_into().unwrap_or([0u8; 32]);
         // let session_id = new_session_id();
         let (abort_handle, abort_reg) = AbortHandle::new_pair();
         let public_ed = VerifyingKey::from_bytes(&public_ed_bytes).unwrap();
-
-        {
-            let mut hub = hub_state.write().await;
-            hub.add(
-                id,
-                session.clone(),
-                abort_handle,
-                ip,
-                public_x,
-                public_ed,
-            );
-        }
-
-        tracing::debug!("WebSocket connected: {}", id);
+        let mut limits = ConnLimits::new(ip.clone());
+
+        let token = hub_state.add(
+            id,
+            hub::Conn::Ws(session.clone()),
+            abort_handle,
+            ip.clone(),
+            public_x,
+            public_ed,
+            if is_user { hub::Route::User } else { hub::Route::Device },
+        );
+
+        span.record("user_id", id);
+        audit_login(pool.get_ref(), Some(id), "login", &ip, audit::OK, json!({ "route": if is_user { "user" } else { "device" } }));
+        tracing::debug!(parent: &span, "WebSocket connected: {}", id);
 
         actix_web::rt::spawn(Abortable::new(async move
         {
-            while let Some(Ok(msg)) = msg_stream.next().await {
+            let _conn_guard = conn_guard;
+            while let Some(msg) = msg_stream.next().await {
+                let msg = match msg {
+                    Ok(m) => m,
+                    Err(actix_ws::ProtocolError::Overflow) => {
+                        tracing::warn!("WS frame from {} too large", id);
+                        hub::Conn::Ws(session.clone()).close_with(actix_ws::CloseCode::Size, "frame too large").await;
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::warn!("WS protocol error from {}: {:?}", id, e);
+                        break;
+                    }
+                };
                 // if !matches!(msg, actix_ws::Message::Pong(_)) { tracing::debug!("WebSocket message: {:?}", msg); }
 
-                {
-                    let mut hub = hub_state.write().await;
-                    hub.renew_heartbeat(id);
-                }
+                hub_state.renew_heartbeat(id);
 
                 match msg {
                     actix_ws::Message::Ping(bytes) => { session.pong(&bytes).await.ok(); continue; }
@@ -284,113 +358,15 @@ RETURNING id"
                     actix_ws::Message::Binary(bytes) => {
                         tracing::info!("New binary message from {} length={}", id, bytes.len());
 
-                        let bytes = bytes.as_ref();
-
-                        if bytes.len() < 5 {
-                            tracing::warn!("❌ Packet too short");
-                            continue;
-                        }
-
-                        // читаем адрес в big-endian
-                        let addr: u32 =
-                            (bytes[0] as u32) |
-                            ((bytes[1] as u32) << 8 ) |
-                            ((bytes[2] as u32) << 16) |
-                            ((bytes[3] as u32) << 24);
-
-                        // пакет адресату
-                        if addr != 0 {
-                            let mut out = bytes.to_vec();
-                            out[0..4].copy_from_slice(&id.to_le_bytes());
-                            if ! send_to(
-                                &hub_state,
-                                addr as UserId,
-                                hub::Outgoing::Binary(out)
-                            ).await {
-                                tracing::warn!("❌ Failed to route to addr {}", addr);
-                                let _ = session.text("Failed to route").await;
-                                continue;
-                            };
-                            tracing::info!("Message routed from {} to {}", id, addr);
-                            continue;
-                        }
-
-                        // пакет серверу
-                        println!("### Message from {} to server {}", id, addr);
-                        let encrypted = &bytes[4..];
-                        tracing::info!("Binary packet: addr={}, encrypted_len={}",addr,encrypted.len());
-                        let bin = match crypto25519::verify_and_decrypt(
-                            encrypted,
-                            &MY_CONFIG.secret_x,
-                            &public_x,
-                            &public_ed,
-                            5, // 5 seconds timeout
-                        ) {
-                            Ok(v) if !v.is_empty() => v,
-
-                            Err(DecryptError::BadNonce) => {
-                                tracing::warn!("❌ decrypt failed: bad nonce");
-                                let _ = session.text(format!("timestamp_error:{}",crypto25519::get_unixtime())).await;
-                                continue;
-                            }
-                            Err(DecryptError::BadSignature) => {
-                                tracing::warn!("❌ decrypt/verify failed: bad signature");
-                                continue;
-                            }
-                            Err(DecryptError::BadFormat) => {
-                                tracing::warn!("❌ decrypt failed: bad format");
-                                continue;
-                            }
-
-                            Ok(_) => {
-                                tracing::warn!("❌ decrypt failed: empty plaintext");
-                                continue;
+                        match packet(id, &public_x, &public_ed, &bytes, pool.get_ref(), &hub_state, &mut limits).await {
+                            Some(hub::Outgoing::Binary(b)) => { let _ = session.binary(b).await; }
+                            Some(hub::Outgoing::Text(t)) => { let _ = session.text(t).await; }
+                            Some(hub::Outgoing::Close) => {
+                                hub::Conn::Ws(session.clone()).close_with(actix_ws::CloseCode::Policy, &ratelimit::ban_reason()).await;
+                                break;
                             }
-
-                            // _ => {
-                            //     tracing::warn!("❌ decrypt/verify failed");
-                            //     continue;
-                            // }
-                        };
-
-
-
-
-
-
-
-
-                        let message_id: u16 = u16::from_le_bytes([bin[0], bin[1]]);
-                        let cmd: u8 = bin[2];
-                        // let body = &bin[3..];
-
-                        // ===================
-                        let body = server(cmd, id, &bin[3..], pool.get_ref(), &hub_state).await;
-
-                        let cmd = 0x01; // ответ
-                        let from = 0u32; // сервер=0
-
-                        // ---
-                        let mut inner = Vec::with_capacity(3 + body.len());
-                        inner.extend_from_slice(&message_id.to_le_bytes()); // id: u16 LE
-                        inner.push(cmd);                            // cmd: u8
-                        inner.extend_from_slice(&body);              // тело
-
-                        let encoded = crypto25519::encrypt_and_sign(
-                            &inner,
-                            &MY_CONFIG.secret_x,
-                            &ed25519_dalek::SigningKey::from_bytes(&MY_CONFIG.secret_ed),
-                            &public_x,
-                        );
-
-                        let mut payload = Vec::with_capacity(4 + encoded.len());
-                        payload.extend_from_slice(&from.to_le_bytes()); // u32 LE
-                        payload.extend_from_slice(&encoded);
-
-                        // ===================
-                        // let reply = "ok";
-                        // let _ = session.text(reply).await;
-                        let _ = session.binary(payload).await;
+                            None => {}
+                        }
                         continue;
                     }
 // ================================================================================
@@ -401,12 +377,9 @@ RETURNING id"
                 }
             }
 
-            {
-               let mut hub = hub_state.write().await;
-               hub.del(id);
-            }
+            hub_state.del(id, token);
             tracing::debug!("WebSocket disconnected by client: {}", id);
-        }, abort_reg ));
+        }.instrument(span), abort_reg ));
     }
     Ok(response)
 }
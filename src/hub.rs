@@ -1,13 +1,13 @@
 use crate::config::CONFIG;
 use crate::MY_CONFIG;
 
-use std::collections::{HashMap};
-use std::sync::Arc;
-// use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use dashmap::DashMap;
 use ed25519_dalek::VerifyingKey;
-use tokio::sync::RwLock;
 use serde_json::{Value, json};
-// use std::time::Instant;
 
 pub type UserId = i32;
 
@@ -16,49 +16,223 @@ pub struct EmailCode {
     pub expires: std::time::Instant,  // когда протухает
 }
 
-#[derive(Default)] // Debug, 
+// Общего RwLock больше нет: карты шардированы (DashMap), heartbeat - атомики внутри сессии.
+// Пакет от одного сокета не ждет ни другие сокеты, ни check_heartbeat.
+// Через await шарды не держим: наружу отдаем только копии (Conn клонируется).
 pub struct HubState {
+    sessions: DashMap<UserId, Session>,
+
+    // разное другое
+    email_codes: Mutex<HashMap<String, EmailCode>>, // высланные ему коды на email
+    subscriptions: DashMap<UserId, HashSet<UserId>>, // device_id -> кто подписан на его телеметрию
+    presence: OnceLock<UnboundedSender<Presence>>, // online/offline события для presence::run
+    cluster: OnceLock<UnboundedSender<cluster::Event>>, // подключения для cluster_sessions, если узлов несколько
+
+    epoch: Instant, // heartbeat-ы - миллисекунды от него
+    generation: AtomicU64, // токены подключений
+}
+
+// одно подключение
+struct Session {
     // основные данные сокета
-    sessions: HashMap<UserId, actix_ws::Session>, // WebSocket sessions - чтобы отправлять ему сообщения
-    public_x: HashMap<UserId, [u8; 32]>, // его X25519 public key
-    public_ed: HashMap<UserId, VerifyingKey>, // его Ed25519 public key
+    conn: Conn, // WebSocket session (или другой транспорт) - чтобы отправлять ему сообщения
+    public_x: [u8; 32], // его X25519 public key
+    public_ed: VerifyingKey, // его Ed25519 public key
     // излишества сокета
-    ip: HashMap<UserId, String>, // его IP адрес нахер не нужен, просто сохранили для информации, ибо где его потом еще взять
+    ip: String, // для audit_log
+    route: Route, // /ws/user или /ws/device (TCP, MQTT - всегда device)
+    connected_at: u64, // unixtime
 
     // для обслуживания сокета
-    heartbeats: HashMap<UserId, std::time::Instant>, // чтобы проверять жив ли
-    serverping: HashMap<UserId, std::time::Instant>, // чтобы его пингать  
-    abort_handles: HashMap<UserId, AbortHandle>, // чтобы  его удалить
+    heartbeat: AtomicU64, // чтобы проверять жив ли
+    timeout_ms: AtomicU64, // свой heartbeat timeout (MQTT keep_alive), 0 - общий из config
+    serverping: AtomicU64, // чтобы его пингать
+    abort_handle: AbortHandle, // чтобы его удалить
+    token: u64, // какое это подключение: del от старого после переподключения не трогает новое
+}
 
-    // разное другое
-    email_codes: HashMap<String, EmailCode>, // высланные ему коды на email
+impl Default for HubState {
+    fn default() -> Self {
+        Self {
+            sessions: DashMap::new(),
+            email_codes: Mutex::new(HashMap::new()),
+            subscriptions: DashMap::new(),
+            presence: OnceLock::new(),
+            cluster: OnceLock::new(),
+            epoch: Instant::now(),
+            generation: AtomicU64::new(0),
+        }
+    }
 }
 
+use crate::presence::Presence;
+use crate::cluster;
+use tokio::sync::mpsc::UnboundedSender;
+
 use futures::future::AbortHandle;
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Route {
+    User,
+    Device,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SessionInfo {
+    pub user_id: UserId,
+    pub ip: String,
+    pub route: &'static str,
+    pub connected_at: u64,
+    pub idle_sec: u64,
+}
+
+// для cluster_sessions: id, token подключения, public_x, public_ed
+pub type ClusterSession = (UserId, u64, [u8; 32], [u8; 32]);
+
+impl Route {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Route::User => "user",
+            Route::Device => "device",
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub enum Outgoing {
     Text(String),
     Binary(Vec<u8>),
+    Close,
+}
+
+// куда слать: WebSocket или канал транспорта без actix (MQTT, ...)
+#[derive(Clone)]
+pub enum Conn {
+    Ws(actix_ws::Session),
+    Channel(UnboundedSender<Outgoing>),
+}
+
+impl Conn {
+    pub async fn send(&mut self, msg: Outgoing) -> bool {
+        match self {
+            Conn::Ws(session) => match msg {
+                Outgoing::Text(s) => session.text(s).await.is_ok(),
+                Outgoing::Binary(b) => session.binary(b).await.is_ok(),
+                Outgoing::Close => session.clone().close(None).await.is_ok(),
+            },
+            Conn::Channel(tx) => tx.send(msg).is_ok(),
+        }
+    }
+
+    // у канальных транспортов свой keepalive
+    pub async fn ping(&mut self) {
+        if let Conn::Ws(session) = self {
+            let _ = session.ping(&[]).await;
+        }
+    }
+
+    // закрыть с кодом и причиной (остановка сервера, переезд на другой узел, ...)
+    pub async fn close_with(self, code: actix_ws::CloseCode, reason: &str) {
+        match self {
+            Conn::Ws(session) => {
+                let _ = session.close(Some(actix_ws::CloseReason {
+                    code,
+                    description: Some(reason.to_string()),
+                })).await;
+            }
+            Conn::Channel(tx) => {
+                let _ = tx.send(Outgoing::Text(reason.to_string()));
+                let _ = tx.send(Outgoing::Close);
+            }
+        }
+    }
 }
 
 impl HubState {
 
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
     pub fn is_online(&self, user_id: UserId, x: &[u8;32], ed: &[u8;32]) -> bool {
-        self.sessions.contains_key(&user_id)
-            && self.public_x.get(&user_id) == Some(x)
-            && self.public_ed.get(&user_id).map(|k| k.as_bytes()) == Some(ed)
+        self.sessions
+            .get(&user_id)
+            .is_some_and(|s| &s.public_x == x && s.public_ed.as_bytes() == ed)
+    }
+
+    pub fn set_presence(&self, tx: UnboundedSender<Presence>) {
+        let _ = self.presence.set(tx);
+    }
+
+    pub fn set_cluster(&self, tx: UnboundedSender<cluster::Event>) {
+        let _ = self.cluster.set(tx);
+    }
+
+    fn cluster_event(&self, ev: cluster::Event) {
+        if let Some(tx) = self.cluster.get() {
+            let _ = tx.send(ev);
+        }
+    }
+
+    fn presence_event(&self, ev: Presence) {
+        if let Some(tx) = self.presence.get() {
+            let _ = tx.send(ev);
+        }
     }
 
-    pub fn get_email_code(&mut self, email: &str) -> (String, bool) {
+    pub fn public_x(&self, user_id: UserId) -> Option<[u8; 32]> {
+        self.sessions.get(&user_id).map(|s| s.public_x)
+    }
+
+    pub fn ip(&self, user_id: UserId) -> Option<String> {
+        self.sessions.get(&user_id).map(|s| s.ip.clone())
+    }
+
+    // копия канала до сессии - отправлять уже без блокировок
+    pub fn conn(&self, user_id: UserId) -> Option<Conn> {
+        self.sessions.get(&user_id).map(|s| s.conn.clone())
+    }
+
+    // первый / последний подписчик устройства на узле -> cluster_subscriptions
+    pub fn subscribe(&self, device_id: UserId, user_id: UserId) {
+        let mut set = self.subscriptions.entry(device_id).or_default();
+        let first = set.is_empty();
+        set.insert(user_id);
+        drop(set);
+        if first {
+            self.cluster_event(cluster::Event::Watched(device_id, true));
+        }
+    }
+
+    pub fn unsubscribe(&self, device_id: UserId, user_id: UserId) -> bool {
+        let removed = match self.subscriptions.get_mut(&device_id) {
+            Some(mut set) => set.remove(&user_id),
+            None => return false,
+        };
+        if self.subscriptions.remove_if(&device_id, |_, set| set.is_empty()).is_some() {
+            self.cluster_event(cluster::Event::Watched(device_id, false));
+        }
+        removed
+    }
+
+    // online subscribers of the device
+    pub fn subscribers(&self, device_id: UserId) -> Vec<UserId> {
+        self.subscriptions
+            .get(&device_id)
+            .map(|set| set.iter().filter(|id| self.sessions.contains_key(id)).copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_email_code(&self, email: &str) -> (String, bool) {
         let now = std::time::Instant::now();
-        self.email_codes.retain(|_, entry| entry.expires > now);
+        let mut email_codes = self.email_codes.lock().unwrap();
+        email_codes.retain(|_, entry| entry.expires > now);
 
-        match self.email_codes.get(email) {
+        match email_codes.get(email) {
             Some(entry) => (format!("{:06}", entry.code), false),
             None => {
                 let c = rand::random::<u32>() % 1_000_000;
-                self.email_codes.insert( email.to_string(),
+                email_codes.insert( email.to_string(),
                  EmailCode {
                         code: c,
                         expires: now + std::time::Duration::from_secs(CONFIG.email_code_expired_sec as u64),
@@ -69,42 +243,157 @@ impl HubState {
         }
     }
 
-    pub fn renew_heartbeat(&mut self, id: UserId) {
-        if self.sessions.contains_key(&id) {
-            let now = std::time::Instant::now();
-            self.heartbeats.insert(id, now);
-            self.serverping.insert(id, now);
-        }
+    pub fn renew_heartbeat(&self, id: UserId) {
+        let Some(s) = self.sessions.get(&id) else {
+            return;
+        };
+        let now = self.now_ms();
+        s.heartbeat.store(now, Ordering::Relaxed);
+        s.serverping.store(now, Ordering::Relaxed);
+        drop(s);
+        self.presence_event(Presence::Seen(id));
     }
 
+    // токен - для del: удаляет только свое подключение
+    // он уже был подключен (переподключился раньше, чем старое отвалилось) - старое закрыть
+    #[allow(clippy::too_many_arguments)]
     pub fn add(
-        &mut self,
+        &self,
         id: UserId,
-        session: actix_ws::Session,
+        session: Conn,
         abort_handle: AbortHandle,
         ip: String,
         public_x: [u8; 32],
         public_ed: VerifyingKey,
-    ) {
-        self.sessions.insert(id, session);
-        self.heartbeats.insert(id, std::time::Instant::now());
-        self.serverping.insert(id, std::time::Instant::now());
-        self.abort_handles.insert(id, abort_handle);
-        self.ip.insert(id, ip);
-        self.public_x.insert(id, public_x);
-        self.public_ed.insert(id, public_ed);
-    }
-
-    pub fn del(&mut self, id: UserId) {
-        self.sessions.remove(&id);
-        self.heartbeats.remove(&id);
-        self.serverping.remove(&id);
-        self.abort_handles.remove(&id);
-        self.ip.remove(&id);
-        self.public_ed.remove(&id);
-        self.public_x.remove(&id);
+        route: Route,
+    ) -> u64 {
+        let now = self.now_ms();
+        let token = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let old = self.sessions.insert(id, Session {
+            conn: session,
+            public_x,
+            public_ed,
+            ip: ip.clone(),
+            route,
+            connected_at: crate::crypto25519::get_unixtime(),
+            heartbeat: AtomicU64::new(now),
+            timeout_ms: AtomicU64::new(0),
+            serverping: AtomicU64::new(now),
+            abort_handle,
+            token,
+        });
+        if let Some(old) = old {
+            old.abort_handle.abort();
+            tokio::spawn(old.conn.close_with(actix_ws::CloseCode::Policy, "replaced by a new connection"));
+            self.unsubscribe_all(id);
+            tracing::debug!("hub.replaced {}", id);
+        }
+        self.presence_event(Presence::Online(id, ip));
+        self.cluster_event(cluster::Event::Connected(id, token, public_x, *public_ed.as_bytes()));
+        token
+    }
+
+    // свой таймаут вместо heartbeat_timeout: клиент сам сказал, как часто будет пинговать
+    pub fn set_timeout(&self, id: UserId, token: u64, timeout: std::time::Duration) {
+        if let Some(s) = self.sessions.get(&id).filter(|s| s.token == token) {
+            s.timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    // false - это подключение уже заменено новым (или закрыто), ничего не трогаем
+    pub fn del(&self, id: UserId, token: u64) -> bool {
+        if self.sessions.remove_if(&id, |_, s| s.token == token).is_none() {
+            tracing::debug!("hub.stale {} (token {})", id, token);
+            return false;
+        }
+        self.presence_event(Presence::Offline(id));
+        self.cluster_event(cluster::Event::Disconnected(id));
+        self.unsubscribe_all(id);
 
         tracing::debug!("hub.disconnected {}, all: {}", id, self.sessions.len());
+        true
+    }
+
+    fn unsubscribe_all(&self, id: UserId) {
+        let mut unwatched = Vec::new();
+        self.subscriptions.retain(|device_id, set| {
+            if set.remove(&id) && set.is_empty() {
+                unwatched.push(*device_id);
+            }
+            !set.is_empty()
+        });
+        for device_id in unwatched {
+            self.cluster_event(cluster::Event::Watched(device_id, false));
+        }
+    }
+
+    // он уже подключен к другому узлу: закрыть здесь молча, без offline событий
+    // token - то подключение, которое тот узел вытеснил; успел вернуться сюда заново - не трогаем
+    pub async fn evict(&self, id: UserId, token: Option<u64>, reason: &str) {
+        if self.close_session(id, token, reason, false).await {
+            tracing::debug!("hub.evicted {}, all: {}", id, self.sessions.len());
+        }
+    }
+
+    // админ выкинул: закрыть с причиной, он offline
+    pub async fn kick(&self, id: UserId, reason: &str) -> bool {
+        let kicked = self.close_session(id, None, reason, true).await;
+        if kicked {
+            tracing::debug!("hub.kicked {}, all: {}", id, self.sessions.len());
+        }
+        kicked
+    }
+
+    // token: None - любое его подключение, Some - только это (не успел ли он переподключиться)
+    async fn close_session(&self, id: UserId, token: Option<u64>, reason: &str, offline: bool) -> bool {
+        let Some((_, s)) = self.sessions.remove_if(&id, |_, s| token.is_none_or(|t| t == s.token)) else {
+            return false;
+        };
+        if offline {
+            self.presence_event(Presence::Offline(id));
+            self.cluster_event(cluster::Event::Disconnected(id));
+        }
+        s.conn.close_with(actix_ws::CloseCode::Policy, reason).await;
+        s.abort_handle.abort();
+        self.unsubscribe_all(id);
+        true
+    }
+
+    // для admin_sessions: по id, с пагинацией
+    pub fn sessions_list(&self, offset: usize, limit: usize) -> (usize, Vec<SessionInfo>) {
+        let now = self.now_ms();
+        let mut list: Vec<SessionInfo> = self.sessions.iter().map(|s| SessionInfo {
+            user_id: *s.key(),
+            ip: s.ip.clone(),
+            route: s.route.as_str(),
+            connected_at: s.connected_at,
+            idle_sec: now.saturating_sub(s.heartbeat.load(Ordering::Relaxed)) / 1000,
+        }).collect();
+        list.sort_by_key(|s| s.user_id);
+        let total = list.len();
+        (total, list.into_iter().skip(offset).take(limit).collect())
+    }
+
+    // cluster rejoin: все сессии + устройства, у которых есть подписчики
+    pub fn cluster_snapshot(&self) -> (Vec<ClusterSession>, Vec<UserId>) {
+        let sessions = self.sessions.iter()
+            .map(|s| (*s.key(), s.token, s.public_x, *s.public_ed.as_bytes()))
+            .collect();
+        let watched = self.subscriptions.iter().map(|s| *s.key()).collect();
+        (sessions, watched)
+    }
+
+    // shutdown: забрать все сессии разом, без offline событий (это не устройства пропали)
+    pub fn drain(&self) -> Vec<Conn> {
+        self.subscriptions.clear();
+        let ids: Vec<UserId> = self.sessions.iter().map(|s| *s.key()).collect();
+        ids.into_iter()
+            .filter_map(|id| self.sessions.remove(&id).map(|(_, s)| s.conn))
+            .collect()
+    }
+
+    pub fn sessions_by_route(&self, route: Route) -> usize {
+        self.sessions.iter().filter(|s| s.route == route).count()
     }
 
     // pub async fn info_users(&self) -> Value {
@@ -120,18 +409,22 @@ impl HubState {
     // }
 
     pub fn info_json(&self) -> Value {
+        let sessions = self.sessions.len();
         json!({
             "started_at": MY_CONFIG.started_at.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
             "uptime_minutes": MY_CONFIG.started_at.elapsed().map(|d| d.as_secs() / 60).unwrap_or(0),
             "uptime_days": MY_CONFIG.started_at.elapsed().map(|d| d.as_secs() / 86400).unwrap_or(0),
             "public_x": hex::encode_upper(&MY_CONFIG.public_x),
             "public_ed": hex::encode_upper(&MY_CONFIG.public_ed),
-            "loglevel": &CONFIG.loglevel,
+            "loglevel": crate::config::runtime().loglevel,
+            "config": crate::config::reload_status(),
             "version": env!("CARGO_PKG_VERSION"),
-            "websockets": self.sessions.len(),
-            "heartbeats": self.heartbeats.len(),
-            "serverping": self.serverping.len(),
-            "loops": self.abort_handles.len(),
+            "websockets": sessions,
+            // heartbeat/ping/abort теперь живут внутри сессии, счетчики оставлены для совместимости
+            "heartbeats": sessions,
+            "serverping": sessions,
+            "loops": sessions,
+            "subscriptions": self.subscriptions.iter().map(|set| set.len()).sum::<usize>(),
             "status": "OK",
         })
     }
@@ -139,74 +432,46 @@ impl HubState {
 }
 
 
-pub fn check_heartbeat(hub_state: Arc<RwLock<HubState>>) {
+pub fn check_heartbeat(hub_state: Arc<HubState>) {
     tokio::spawn(async move {
         let mut ticker = tokio::time::interval(std::time::Duration::from_secs(2));
         loop {
             ticker.tick().await;
-
-            let now = std::time::Instant::now();
-            let timelimit = now - std::time::Duration::from_secs(CONFIG.heartbeat_timeout);
-            let pinglimit = now - std::time::Duration::from_secs(CONFIG.ping_timeout);
-
-            let hub = hub_state.read().await;
-
-            let ids_expired: Vec<UserId> = hub
-                .heartbeats
-                .iter()
-                .filter_map(
-                    |(&sid, &last)| {
-                        if last < timelimit { Some(sid) } else { None }
-                    },
-                )
-                .collect();
-
-            let expired_sessions: Vec<actix_ws::Session> = ids_expired
-                .iter()
-                .filter_map(|sid| hub.sessions.get(sid).cloned())
-                .collect();
-
-            let ids_to_ping: Vec<UserId> = hub
-                .serverping
-                .iter()
-                .filter_map(|(&sid, &last_ping)| {
-                    if last_ping < pinglimit {
-                        Some(sid)
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            
-            drop(hub);
-
-            for session in &expired_sessions {
-                let _ = session.clone().close(None).await;
+            if crate::shutdown::is_shutting_down() {
+                tracing::debug!("check_heartbeat stopped");
+                break;
             }
 
-            if !ids_to_ping.is_empty() || !ids_expired.is_empty() {
-
-                let mut hub = hub_state.write().await;
-
-                for sid in &ids_expired {
-                    if let Some(abort_handle) = hub.abort_handles.get(sid) {
-                        abort_handle.abort();
-                    }
-                    tracing::debug!("WebSocket disconnected by timeout: {}", sid);
-                    hub.del(*sid);
+            let now = hub_state.now_ms();
+            let rt = crate::config::runtime();
+            let timelimit = now.saturating_sub(rt.heartbeat_timeout * 1000);
+            let pinglimit = now.saturating_sub(rt.ping_timeout * 1000);
+
+            // сначала только собрать, ping/close - уже без шардов
+            let mut expired: Vec<(UserId, u64)> = Vec::new();
+            let mut to_ping: Vec<Conn> = Vec::new();
+            for s in hub_state.sessions.iter() {
+                let timelimit = match s.timeout_ms.load(Ordering::Relaxed) {
+                    0 => timelimit,
+                    own => now.saturating_sub(own),
+                };
+                if s.heartbeat.load(Ordering::Relaxed) < timelimit {
+                    expired.push((*s.key(), s.token));
+                } else if s.serverping.load(Ordering::Relaxed) < pinglimit {
+                    s.serverping.store(now, Ordering::Relaxed);
+                    to_ping.push(s.conn.clone());
                 }
+            }
 
-                for sid in &ids_to_ping {
-                    if ids_expired.contains(sid) {
-                        continue;
-                    }
-
-                    if let Some(session) = hub.sessions.get_mut(sid) {
-                        let _ = session.ping(&[]).await;
-                    }
-                    hub.serverping.insert(*sid, now);
+            // по токену: пока закрывали предыдущих, он мог переподключиться
+            for (sid, token) in expired {
+                if hub_state.close_session(sid, Some(token), "heartbeat timeout", true).await {
+                    tracing::debug!("WebSocket disconnected by timeout: {}", sid);
                 }
+            }
 
+            for mut session in to_ping {
+                session.ping().await;
             }
         }
     });
@@ -215,18 +480,158 @@ pub fn check_heartbeat(hub_state: Arc<RwLock<HubState>>) {
 // =================================================================
 
 pub async fn send_to(
-    hub_state: &Arc<RwLock<HubState>>,
+    hub_state: &Arc<HubState>,
+    to: UserId,
+    msg: Outgoing,
+) -> bool {
+    if hub_state.sessions.contains_key(&to) {
+        return send_local(hub_state, to, msg).await;
+    }
+    match cluster::get() {
+        Some(c) => c.forward(to, msg).await,
+        None => false,
+    }
+}
+
+// только сессии этого узла
+pub async fn send_local(
+    hub_state: &Arc<HubState>,
     to: UserId,
     msg: Outgoing,
 ) -> bool {
-    let hub = hub_state.read().await;
-    let Some(mut session) = hub.sessions.get(&to).cloned() else {
+    let Some(mut session) = hub_state.conn(to) else {
         return false;
     };
-    drop(hub);
+    session.send(msg).await
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    // переподключился раньше, чем старое подключение отвалилось: его del не трогает новое
+    #[tokio::test]
+    async fn reconnect_then_stale_del() {
+        let hub = HubState::default();
+        let ed = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        let (ptx, mut presence) = mpsc::unbounded_channel();
+        hub.set_presence(ptx);
+
+        let (old_tx, mut old_rx) = mpsc::unbounded_channel();
+        let (old_abort, old_reg) = AbortHandle::new_pair();
+        let old = hub.add(5, Conn::Channel(old_tx), old_abort, "10.0.0.1".into(), [1u8; 32], ed, Route::Device);
+        hub.subscribe(9, 5);
+
+        let (new_tx, _new_rx) = mpsc::unbounded_channel();
+        let (new_abort, _) = AbortHandle::new_pair();
+        let new = hub.add(5, Conn::Channel(new_tx), new_abort, "10.0.0.2".into(), [2u8; 32], ed, Route::Device);
+        assert_ne!(old, new);
+
+        // старое закрыто и его цикл остановлен
+        assert!(matches!(old_rx.recv().await, Some(Outgoing::Text(_))));
+        assert!(matches!(old_rx.recv().await, Some(Outgoing::Close)));
+        assert!(old_reg.handle().is_aborted());
+
+        // del от старого цикла - поздно, ничего не трогает
+        hub.subscribe(9, 5);
+        assert!(!hub.del(5, old));
+        assert_eq!(hub.public_x(5), Some([2u8; 32]));
+        assert_eq!(hub.subscribers(9), vec![5]);
+
+        // heartbeat по старому токену тоже
+        assert!(!hub.close_session(5, Some(old), "heartbeat timeout", true).await);
+        assert!(hub.conn(5).is_some());
+
+        assert!(hub.del(5, new));
+        assert!(hub.conn(5).is_none());
+        assert!(hub.subscribers(9).is_empty());
+
+        let mut events = Vec::new();
+        while let Ok(ev) = presence.try_recv() {
+            events.push(ev);
+        }
+        assert_eq!(events.iter().filter(|e| matches!(e, Presence::Offline(5))).count(), 1);
+    }
+
+    // A -> B -> A: Evict от B пришел, когда он уже снова здесь - новое подключение живет
+    #[tokio::test]
+    async fn late_evict_keeps_new_session() {
+        let hub = HubState::default();
+        let ed = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        let (ctx, mut cluster) = mpsc::unbounded_channel();
+        hub.set_cluster(ctx);
+
+        let (old_tx, _old_rx) = mpsc::unbounded_channel();
+        let (old_abort, _) = AbortHandle::new_pair();
+        let old = hub.add(5, Conn::Channel(old_tx), old_abort, "10.0.0.1".into(), [1u8; 32], ed, Route::Device);
+        assert!(hub.del(5, old)); // ушел на B
+
+        let (new_tx, mut new_rx) = mpsc::unbounded_channel();
+        let (new_abort, new_reg) = AbortHandle::new_pair();
+        let new = hub.add(5, Conn::Channel(new_tx), new_abort, "10.0.0.1".into(), [1u8; 32], ed, Route::Device);
+
+        hub.evict(5, Some(old), "session moved to another server").await;
+        assert!(hub.conn(5).is_some());
+        assert!(new_rx.try_recv().is_err());
+
+        let (sessions, _) = hub.cluster_snapshot();
+        assert_eq!(sessions, vec![(5, new, [1u8; 32], *ed.as_bytes())]);
+
+        hub.evict(5, Some(new), "session moved to another server").await;
+        assert!(hub.conn(5).is_none());
+        assert!(new_reg.handle().is_aborted());
+
+        // evict без offline: Disconnected в cluster только от del
+        let mut disconnected = 0;
+        while let Ok(ev) = cluster.try_recv() {
+            if matches!(ev, cluster::Event::Disconnected(5)) {
+                disconnected += 1;
+            }
+        }
+        assert_eq!(disconnected, 1);
+    }
+
+    // benchmark: cargo test --release routing_10k -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn routing_10k_sessions() {
+        const SESSIONS: i32 = 10_000;
+        const SENDERS: i32 = 8;
+        const PER_SENDER: i32 = 100_000;
+
+        let hub = Arc::new(HubState::default());
+        let ed = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        for id in 1..=SESSIONS {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let (abort, _) = AbortHandle::new_pair();
+            hub.add(id, Conn::Channel(tx), abort, "127.0.0.1".into(), [0u8; 32], ed, Route::Device);
+            tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        }
+
+        let started = Instant::now();
+        let mut tasks = Vec::new();
+        for s in 0..SENDERS {
+            let hub = hub.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..PER_SENDER {
+                    let to = (i * 7919 + s * 104_729) % SESSIONS + 1;
+                    hub.renew_heartbeat(to); // как handlers_ws на каждый входящий фрейм
+                    assert!(send_to(&hub, to, Outgoing::Binary(vec![0u8; 64])).await);
+                }
+            }));
+        }
+        for t in tasks {
+            t.await.unwrap();
+        }
 
-    match msg {
-        Outgoing::Text(s) => session.text(s).await.is_ok(),
-        Outgoing::Binary(b) => session.binary(b).await.is_ok(),
+        let elapsed = started.elapsed();
+        let total = (SENDERS * PER_SENDER) as f64;
+        println!(
+            "routed {} packets between {} sessions in {:?}: {:.0} packets/sec",
+            total, SESSIONS, elapsed, total / elapsed.as_secs_f64()
+        );
     }
 }
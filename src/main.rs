@@ -9,6 +9,7 @@
 
 mod server;
 mod server_0x00;
+mod protocol;
 
 use actix_cors::Cors;
 use actix_web::{
@@ -19,6 +20,7 @@ use actix_web::{
 
 mod config;
 mod handlers_ws;
+mod handlers_http;
 
 // mod ws_engine;
 // use crate::ws_engine::*;
@@ -31,7 +33,24 @@ use config::CONFIG;
 use hex::FromHex;
 
 mod email;
+mod notify;
+mod alerts;
+mod presence;
+mod webhooks;
+mod mqtt;
+mod tcp;
+mod udp;
+mod ratelimit;
+mod connlimit;
+mod tls;
+mod audit;
+mod roles;
+mod accounts;
+mod metrics;
+mod shutdown;
+mod cluster;
 mod postgres;
+mod cli;
 mod crypto25519;
 use crate::crypto25519::*;
 
@@ -39,10 +58,13 @@ use crate::hub::check_heartbeat;
 
 use actix_files::Files;
 
-fn initialize_tracing() {
-    use tracing_subscriber::{filter::targets::Targets, prelude::*};
+use tracing_subscriber::{filter::targets::Targets, reload, Registry};
+
+// уровень лога меняется на лету (config::reload)
+static LOG_FILTER: std::sync::OnceLock<reload::Handle<Targets, Registry>> = std::sync::OnceLock::new();
 
-    let level = match CONFIG.loglevel.as_str() {
+fn log_targets(loglevel: &str) -> Targets {
+    let level = match loglevel {
         "TRACE" => tracing::Level::TRACE, // full
         "DEBUG" => tracing::Level::DEBUG, // for developer
         "INFO" => tracing::Level::INFO,   // normal
@@ -50,15 +72,33 @@ fn initialize_tracing() {
         "ERROR" => tracing::Level::ERROR, // serious error
         _ => tracing::Level::TRACE,
     };
+    Targets::new()
+        .with_target(env!("CARGO_BIN_NAME"), level)
+        .with_target("actix", tracing::Level::WARN)
+}
+
+pub fn set_loglevel(loglevel: &str) {
+    if let Some(handle) = LOG_FILTER.get()
+        && let Err(e) = handle.reload(log_targets(loglevel))
+    {
+        tracing::error!("Log level reload failed: {:?}", e);
+    }
+}
+
+// log_format = "json" - одна строка JSON на событие, со всеми открытыми span-ами (для Loki / Elasticsearch)
+fn initialize_tracing() {
+    use tracing_subscriber::prelude::*;
+
+    let (filter, handle) = reload::Layer::new(log_targets(&CONFIG.loglevel));
+    let _ = LOG_FILTER.set(handle);
 
-    tracing_subscriber::registry()
-        .with(
-            Targets::new()
-                .with_target(env!("CARGO_BIN_NAME"), level)
-                .with_target("actix", tracing::Level::WARN),
-        )
-        .with(tracing_subscriber::fmt::layer().compact())
-        .init();
+    let registry = tracing_subscriber::registry().with(filter);
+    let fmt = tracing_subscriber::fmt::layer();
+    if CONFIG.log_format == "json" {
+        registry.with(fmt.json().with_current_span(true).with_span_list(true)).init();
+    } else {
+        registry.with(fmt.compact()).init();
+    }
 }
 
 
@@ -84,30 +124,45 @@ pub static MY_CONFIG: LazyLock<MyConfig> = LazyLock::new(|| {
         started_at: std::time::SystemTime::now(),
         secret_x,
         public_x,
-        secret_ed: secret_ed.as_bytes().clone(),
+        secret_ed: *secret_ed.as_bytes(),
         public_ed,
     }
 });
 
 use sqlx::{postgres::PgPoolOptions, migrate::Migrator};
 
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
+    use clap::Parser;
+    match cli::Cli::parse().command {
+        None | Some(cli::Command::Serve) => serve().await,
+        Some(command) => {
+            if let Err(e) = cli::run(command).await {
+                eprintln!("error: {:#}", e);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
     initialize_tracing();
     tracing::info!("{}/{}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
 
-    println!("Starting Aguardia WS server...");
-    static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
-    let pool = PgPoolOptions::new().max_connections(5).connect(CONFIG.postgres.as_str()).await?;
+    let pool = PgPoolOptions::new()
+        .min_connections(CONFIG.db_pool_min)
+        .max_connections(CONFIG.db_pool_max)
+        .acquire_timeout(std::time::Duration::from_secs(CONFIG.db_acquire_timeout_sec))
+        .connect(CONFIG.postgres.as_str()).await?;
     if let Err(e) = MIGRATOR.run(&pool).await { panic!("MIGRATE ERROR: {:?}", e); }
-    println!("Postgress: ready");
-
-    if CONFIG.seed_x == "" || CONFIG.seed_ed == "" {
-        panic!("Crypto seeds are not set! Please set AG_SEED_X and AG_SEED_ED environment variables:
-        AG_SEED_X={:?}
-        AG_SEED_ED={:?}",
-        hex::encode_upper(&crypto25519::seed()),
-        hex::encode_upper(&crypto25519::seed())
+    tracing::info!("Postgres: ready");
+
+    if CONFIG.seed_x.is_empty() || CONFIG.seed_ed.is_empty() {
+        panic!("Crypto seeds are not set! Please set AG_SEED_X and AG_SEED_ED environment variables (`{} keygen` prints new ones)",
+        env!("CARGO_BIN_NAME")
         );
     }
 
@@ -158,40 +213,80 @@ async fn main() -> anyhow::Result<()> {
     // ).await?;
     // println!("Test email: sent");
 
-    println!("Server listening on {}:{}", CONFIG.bind_host, CONFIG.bind_port);
-    println!("WS heartbeat timeout: {} sec", CONFIG.heartbeat_timeout);
-    println!("WS ping timeout: {} sec", CONFIG.ping_timeout);
-    println!("Email code expired sec: {}", CONFIG.email_code_expired_sec);
-    println!("Admins: {:?}", CONFIG.admins);
+    tracing::info!(
+        heartbeat_timeout = CONFIG.heartbeat_timeout,
+        ping_timeout = CONFIG.ping_timeout,
+        email_code_expired_sec = CONFIG.email_code_expired_sec,
+        admins = ?CONFIG.admins,
+        "Server listening on {}:{}", CONFIG.bind_host, if CONFIG.bind_port != 0 { CONFIG.bind_port } else { CONFIG.tls_port }
+    );
 
     // starting HubService
-    let hub_state = Arc::new(RwLock::new(HubState::default()));
+    let hub_state = Arc::new(HubState::default());
+
+    // SIGHUP - перечитать config (admins, loglevel, таймауты)
+    config::watch();
 
     // starting heartbeat checker
     check_heartbeat(hub_state.clone());
 
-    let socket = std::net::SocketAddr::new(CONFIG.bind_host.as_str().parse()?, CONFIG.bind_port);
+    // device online/offline notifications
+    presence::start(pool.clone(), hub_state.clone());
 
-    let url = format!("http://{}:{}", &CONFIG.bind_host, &CONFIG.bind_port);
+    audit::start(pool.clone());
+    webhooks::start(pool.clone());
+
+    if CONFIG.cluster {
+        cluster::start(pool.clone(), hub_state.clone()).await?;
+    }
+
+    if CONFIG.mqtt_port != 0 {
+        mqtt::start(pool.clone(), hub_state.clone(), &CONFIG.bind_host, CONFIG.mqtt_port)?;
+    }
+
+    if CONFIG.tcp_port != 0 {
+        tcp::start(pool.clone(), hub_state.clone(), &CONFIG.bind_host, CONFIG.tcp_port)?;
+    }
+
+    if CONFIG.udp_port != 0 {
+        udp::start(pool.clone(), hub_state.clone(), &CONFIG.bind_host, CONFIG.udp_port)?;
+    }
+
+    // bind_port = 0 - только TLS (что tls_port задан, проверяет Config::validate)
+    let (scheme, ws_scheme, port) = if CONFIG.bind_port != 0 { ("http", "ws", CONFIG.bind_port) } else { ("https", "wss", CONFIG.tls_port) };
+    let url = format!("{}://{}:{}", scheme, &CONFIG.bind_host, port);
     tracing::info!("Server running at {}", &url);
     tracing::info!("Log level: {}", &CONFIG.loglevel);
     tracing::info!("API: {}/api", &url);
     tracing::info!(
         "WS: {}/ws",
-        format!("ws://{}:{}", &CONFIG.bind_host, &CONFIG.bind_port)
+        format!("{}://{}:{}", ws_scheme, &CONFIG.bind_host, port)
     );
     tracing::info!("Status: {}/status", &url);
+    tracing::info!("Metrics: {}/metrics", &url);
 
     use std::sync::Arc;
-    use tokio::sync::RwLock;
+
+    let shutdown_hub = hub_state.clone();
+    let pool_for_close = pool.clone();
 
     let server = HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
+        let mut cors = Cors::default()
             .allow_any_method()
             .allow_any_header()
             .supports_credentials()
             .max_age(3600);
+        if CONFIG.cors_origins.iter().any(|o| o == "*") {
+            cors = cors.allow_any_origin();
+        } else {
+            for origin in &CONFIG.cors_origins {
+                cors = cors.allowed_origin(origin);
+            }
+        }
+        let cache_control = match CONFIG.static_cache_sec {
+            0 => "no-cache".to_string(),
+            sec => format!("public, max-age={}", sec),
+        };
 
         App::new()
             .app_data(web::Data::new(pool.clone()))
@@ -200,28 +295,53 @@ async fn main() -> anyhow::Result<()> {
             .wrap(cors)
             .route("/ws/user/v1/{public_ed}", web::get().to(handlers_ws::handler))
             .route("/ws/device/v1/{public_ed}", web::get().to(handlers_ws::handler))
+            .route("/api/v1/packet", web::post().to(handlers_http::packet))
+            .route("/metrics", web::get().to(metrics::handler))
             .route("/status", web::get().to({
-                    move |hub_state: web::Data<Arc<RwLock<HubState>>>| {
+                    move |hub_state: web::Data<Arc<HubState>>| {
                         let hub_state = hub_state.clone();
                         async move {
-                            let info = {
-                              let hub = hub_state.read().await;
-                              hub.info_json()
-                            };
+                            let info = hub_state.info_json();
                             Ok::<_, actix_web::Error>(HttpResponse::Ok().json(info))
                         }
                     }
                 }),
             )
             .service(
-                Files::new("/", &CONFIG.site_dir)
-                .index_file("index.html")
+                web::scope("")
+                .wrap(middleware::DefaultHeaders::new().add((actix_web::http::header::CACHE_CONTROL, cache_control)))
+                .service(
+                    Files::new("/", &CONFIG.site_dir)
+                    .index_file("index.html")
+                )
             )
-    })
-    .bind(socket)?
+    });
+
+    let server = if CONFIG.bind_port != 0 {
+        server.bind(std::net::SocketAddr::new(CONFIG.bind_host.as_str().parse()?, CONFIG.bind_port))?
+    } else {
+        server
+    };
+
+    let server = if CONFIG.tls_port != 0 {
+        let tls_socket = std::net::SocketAddr::new(CONFIG.bind_host.as_str().parse()?, CONFIG.tls_port);
+        tracing::info!("TLS: https://{}:{}", &CONFIG.bind_host, CONFIG.tls_port);
+        server.bind_rustls_0_23(tls_socket, tls::server_config()?)?
+    } else {
+        server
+    };
+
+    let server = server
+    .disable_signals() // SIGTERM обрабатывает shutdown::start
+    .shutdown_timeout(CONFIG.shutdown_timeout)
     .run();
 
+    shutdown::start(server.handle(), shutdown_hub);
+
     server.await?;
 
+    pool_for_close.close().await;
+    tracing::info!("Server stopped");
+
     Ok(())
 }
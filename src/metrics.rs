@@ -0,0 +1,191 @@
+// /metrics в текстовом формате Prometheus
+// счетчики - простые атомики, инкрементятся по месту; сессии и пул БД считаются в момент запроса
+
+use actix_web::{HttpResponse, web};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use crate::crypto25519::DecryptError;
+use crate::hub::{HubState, Route};
+
+pub static PACKETS_ROUTED: AtomicU64 = AtomicU64::new(0);
+pub static PACKETS_ROUTE_FAILED: AtomicU64 = AtomicU64::new(0);
+pub static INSERTS: AtomicU64 = AtomicU64::new(0);
+pub static INSERT_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static EMAILS_SENT: AtomicU64 = AtomicU64::new(0);
+pub static EMAILS_FAILED: AtomicU64 = AtomicU64::new(0);
+pub static RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+pub static BANS: AtomicU64 = AtomicU64::new(0);
+pub static AUDIT_COALESCED: AtomicU64 = AtomicU64::new(0);
+
+static DECRYPT_BAD_NONCE: AtomicU64 = AtomicU64::new(0);
+static DECRYPT_BAD_SIGNATURE: AtomicU64 = AtomicU64::new(0);
+static DECRYPT_BAD_FORMAT: AtomicU64 = AtomicU64::new(0);
+
+// секунды
+const BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+#[derive(Default, Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS.len()], // не накопительные, складываем при выводе
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, n) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += n;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct ActionStats {
+    ok: u64,
+    err: u64,
+    latency: Histogram,
+}
+
+static ACTIONS: LazyLock<Mutex<HashMap<String, ActionStats>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn decrypt_failed(e: &DecryptError) {
+    inc(match e {
+        DecryptError::BadNonce => &DECRYPT_BAD_NONCE,
+        DecryptError::BadSignature => &DECRYPT_BAD_SIGNATURE,
+        DecryptError::BadFormat => &DECRYPT_BAD_FORMAT,
+    });
+}
+
+// action - только из известных (неизвестные приходят как "unknown", чтобы не раздувать метки)
+pub fn action(action: &str, ok: bool, elapsed: Duration) {
+    let mut actions = ACTIONS.lock().unwrap();
+    let stats = actions.entry(action.to_string()).or_default();
+    if ok { stats.ok += 1; } else { stats.err += 1; }
+    stats.latency.observe(elapsed.as_secs_f64());
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn load(c: &AtomicU64) -> u64 {
+    c.load(Ordering::Relaxed)
+}
+
+pub fn render(hub: &HubState, pool: &PgPool) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP aguardia_sessions Connected sessions by route.");
+    let _ = writeln!(out, "# TYPE aguardia_sessions gauge");
+    let _ = writeln!(out, "aguardia_sessions{{route=\"user\"}} {}", hub.sessions_by_route(Route::User));
+    let _ = writeln!(out, "aguardia_sessions{{route=\"device\"}} {}", hub.sessions_by_route(Route::Device));
+
+    let _ = writeln!(out, "# HELP aguardia_connections Open WS/TCP connections, incl. pending logins.");
+    let _ = writeln!(out, "# TYPE aguardia_connections gauge");
+    let _ = writeln!(out, "aguardia_connections {}", crate::connlimit::connections());
+    let _ = writeln!(out, "# HELP aguardia_pending_logins Login sockets waiting for an email code.");
+    let _ = writeln!(out, "# TYPE aguardia_pending_logins gauge");
+    let _ = writeln!(out, "aguardia_pending_logins {}", crate::connlimit::pending_logins());
+
+    counter(&mut out, "aguardia_packets_routed_total", "Packets relayed to another session.", load(&PACKETS_ROUTED));
+    counter(&mut out, "aguardia_packets_route_failed_total", "Packets whose recipient was offline.", load(&PACKETS_ROUTE_FAILED));
+
+    let _ = writeln!(out, "# HELP aguardia_decrypt_failures_total Packets rejected by verify_and_decrypt.");
+    let _ = writeln!(out, "# TYPE aguardia_decrypt_failures_total counter");
+    let _ = writeln!(out, "aguardia_decrypt_failures_total{{kind=\"bad_nonce\"}} {}", load(&DECRYPT_BAD_NONCE));
+    let _ = writeln!(out, "aguardia_decrypt_failures_total{{kind=\"bad_signature\"}} {}", load(&DECRYPT_BAD_SIGNATURE));
+    let _ = writeln!(out, "aguardia_decrypt_failures_total{{kind=\"bad_format\"}} {}", load(&DECRYPT_BAD_FORMAT));
+
+    {
+        let actions = ACTIONS.lock().unwrap();
+        let mut names: Vec<&String> = actions.keys().collect();
+        names.sort();
+
+        let _ = writeln!(out, "# HELP aguardia_actions_total 0x00 actions handled.");
+        let _ = writeln!(out, "# TYPE aguardia_actions_total counter");
+        for name in &names {
+            let s = &actions[*name];
+            let _ = writeln!(out, "aguardia_actions_total{{action=\"{}\",result=\"ok\"}} {}", name, s.ok);
+            let _ = writeln!(out, "aguardia_actions_total{{action=\"{}\",result=\"error\"}} {}", name, s.err);
+        }
+
+        let _ = writeln!(out, "# HELP aguardia_action_duration_seconds 0x00 action latency.");
+        let _ = writeln!(out, "# TYPE aguardia_action_duration_seconds histogram");
+        for name in &names {
+            actions[*name].latency.render(&mut out, "aguardia_action_duration_seconds", &format!("action=\"{}\"", name));
+        }
+    }
+
+    counter(&mut out, "aguardia_data_inserts_total", "Telemetry rows inserted (0x10, MQTT, UDP).", load(&INSERTS));
+    counter(&mut out, "aguardia_data_insert_errors_total", "Telemetry inserts that failed.", load(&INSERT_ERRORS));
+    counter(&mut out, "aguardia_emails_sent_total", "Emails sent.", load(&EMAILS_SENT));
+    counter(&mut out, "aguardia_emails_failed_total", "Emails that failed to send.", load(&EMAILS_FAILED));
+
+    counter(&mut out, "aguardia_rate_limited_total", "Packets rejected by flood protection.", load(&RATE_LIMITED));
+    counter(&mut out, "aguardia_bans_total", "Temporary bans after repeated violations.", load(&BANS));
+    counter(&mut out, "aguardia_audit_coalesced_total", "Repeated denials not written to audit_log.", load(&AUDIT_COALESCED));
+
+    let size = pool.size();
+    let idle = pool.num_idle() as u32;
+    let _ = writeln!(out, "# HELP aguardia_db_pool_connections DB pool connections by state.");
+    let _ = writeln!(out, "# TYPE aguardia_db_pool_connections gauge");
+    let _ = writeln!(out, "aguardia_db_pool_connections{{state=\"idle\"}} {}", idle);
+    let _ = writeln!(out, "aguardia_db_pool_connections{{state=\"in_use\"}} {}", size.saturating_sub(idle));
+
+    out
+}
+
+pub async fn handler(
+    hub_state: web::Data<Arc<HubState>>,
+    db: web::Data<PgPool>,
+) -> HttpResponse {
+    let body = render(hub_state.get_ref(), db.get_ref());
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_is_cumulative() {
+        let mut h = Histogram::default();
+        h.observe(0.0005);
+        h.observe(0.02);
+        h.observe(10.0);
+        let mut out = String::new();
+        h.render(&mut out, "x", "a=\"b\"");
+        assert!(out.contains("x_bucket{a=\"b\",le=\"0.001\"} 1\n"));
+        assert!(out.contains("x_bucket{a=\"b\",le=\"0.025\"} 2\n"));
+        assert!(out.contains("x_bucket{a=\"b\",le=\"5\"} 2\n"));
+        assert!(out.contains("x_bucket{a=\"b\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("x_count{a=\"b\"} 3\n"));
+    }
+}
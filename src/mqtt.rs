@@ -0,0 +1,513 @@
+// Встроенный MQTT 3.1.1 listener (QoS 0/1) для железа без WebSocket + crypto25519.
+//
+//   CONNECT:   username = user id, password = токен из {"action":"set_mqtt_token"}
+//   PUBLISH    aguardia/{id}/telemetry  -> как cmd 0x10 (server::insert_data)
+//   SUBSCRIBE  aguardia/{id}/inbox      <- всё, что hub::send_to шлет этому id (до SUBSCRIBE копится)
+//   keep_alive: нет пакетов 1.5 * keep_alive - отключаем (0 - общий heartbeat_timeout)
+// Лимиты как у WS / TCP: max_connections, max_connections_per_ip (сразу закрываем), пакет не больше max_frame_size.
+
+use futures::future::{AbortHandle, Abortable};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use ed25519_dalek::VerifyingKey;
+use crate::config::CONFIG;
+use crate::connlimit;
+use crate::hub::{Conn, HubState, Route, Outgoing, UserId};
+use crate::ratelimit::{self, ConnLimits, Kind, Verdict};
+use crate::server::{insert_data, limit};
+
+const MAX_PACKET: usize = 256 * 1024; // если max_frame_size = 0
+const MAX_PENDING: usize = 100; // сообщений в inbox до SUBSCRIBE, дальше старые выбрасываются
+
+pub const CONNECT: u8 = 1;
+pub const PUBLISH: u8 = 3;
+pub const SUBSCRIBE: u8 = 8;
+pub const UNSUBSCRIBE: u8 = 10;
+pub const PINGREQ: u8 = 12;
+pub const DISCONNECT: u8 = 14;
+
+// CONNACK return codes
+const ACCEPTED: u8 = 0;
+const BAD_PROTOCOL: u8 = 1;
+const NOT_AUTHORIZED: u8 = 5;
+
+// MQTT: нет пакетов 1.5 * keep_alive - отключить; keep_alive 0 - общий heartbeat_timeout
+pub fn keep_alive_timeout(keep_alive: u16) -> Option<Duration> {
+    (keep_alive > 0).then(|| Duration::from_millis(keep_alive as u64 * 1500))
+}
+
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn telemetry_topic(id: UserId) -> String {
+    format!("aguardia/{}/telemetry", id)
+}
+
+pub fn inbox_topic(id: UserId) -> String {
+    format!("aguardia/{}/inbox", id)
+}
+
+// ==================== codec ====================
+
+pub fn encode_len(mut len: usize, out: &mut Vec<u8>) {
+    loop {
+        let mut b = (len % 128) as u8;
+        len /= 128;
+        if len > 0 { b |= 0x80; }
+        out.push(b);
+        if len == 0 { break; }
+    }
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(5 + body.len());
+    out.push(header);
+    encode_len(body.len(), &mut out);
+    out.extend_from_slice(body);
+    out
+}
+
+pub fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(PUBLISH << 4, &body)
+}
+
+pub fn max_packet() -> usize {
+    if CONFIG.max_frame_size == 0 { MAX_PACKET } else { CONFIG.max_frame_size }
+}
+
+// (тип пакета, флаги, тело); None - соединение закрыто; тело больше max - ошибка, до чтения
+pub async fn read_packet<R: AsyncRead + Unpin>(r: &mut R, max: usize) -> std::io::Result<Option<(u8, u8, Vec<u8>)>> {
+    let mut first = [0u8; 1];
+    if r.read(&mut first).await? == 0 {
+        return Ok(None);
+    }
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        let b = r.read_u8().await?;
+        len |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 { break; }
+        shift += 7;
+        if shift > 21 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad remaining length"));
+        }
+    }
+    if len > max {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "packet too large"));
+    }
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body).await?;
+    Ok(Some((first[0] >> 4, first[0] & 0x0F, body)))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        let out = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(out)
+    }
+    fn str(&mut self) -> Option<&'a str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+    fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos.min(self.buf.len())..]
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Connect {
+    pub protocol_ok: bool,
+    pub keep_alive: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+pub fn parse_connect(body: &[u8]) -> Option<Connect> {
+    let mut r = Reader { buf: body, pos: 0 };
+    let name = r.str()?;
+    let level = r.u8()?;
+    let flags = r.u8()?;
+    let keep_alive = r.u16()?;
+    let client_id = r.str()?.to_string();
+    if flags & 0x04 != 0 {
+        r.str()?;   // will topic
+        r.bytes()?; // will message
+    }
+    let username = if flags & 0x80 != 0 { Some(r.str()?.to_string()) } else { None };
+    let password = if flags & 0x40 != 0 { Some(r.bytes()?.to_vec()) } else { None };
+    Some(Connect {
+        protocol_ok: (name == "MQTT" && level == 4) || (name == "MQIsdp" && level == 3),
+        keep_alive,
+        client_id,
+        username,
+        password,
+    })
+}
+
+// (topic, packet id при QoS>0, payload)
+pub fn parse_publish(flags: u8, body: &[u8]) -> Option<(String, Option<u16>, Vec<u8>)> {
+    let qos = (flags >> 1) & 0x03;
+    let mut r = Reader { buf: body, pos: 0 };
+    let topic = r.str()?.to_string();
+    let pid = if qos > 0 { Some(r.u16()?) } else { None };
+    Some((topic, pid, r.rest().to_vec()))
+}
+
+// (packet id, [topic filter])
+pub fn parse_subscribe(body: &[u8], with_qos: bool) -> Option<(u16, Vec<String>)> {
+    let mut r = Reader { buf: body, pos: 0 };
+    let pid = r.u16()?;
+    let mut topics = Vec::new();
+    while r.pos < body.len() {
+        topics.push(r.str()?.to_string());
+        if with_qos { r.u8()?; }
+    }
+    Some((pid, topics))
+}
+
+// ==================== inbox ====================
+
+// что из hub отдать клиенту сейчас: подписан - сразу, нет - в очередь до SUBSCRIBE
+#[derive(Default)]
+struct Inbox {
+    subscribed: bool,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Inbox {
+    fn push(&mut self, payload: Vec<u8>) -> Vec<Vec<u8>> {
+        if self.subscribed {
+            return vec![payload];
+        }
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(payload);
+        Vec::new()
+    }
+
+    fn subscribe(&mut self, on: bool) -> Vec<Vec<u8>> {
+        self.subscribed = on;
+        if on { self.pending.drain(..).collect() } else { Vec::new() }
+    }
+}
+
+// ==================== listener ====================
+
+pub fn start(pool: PgPool, hub_state: Arc<HubState>, host: &str, port: u16) -> anyhow::Result<()> {
+    let addr = std::net::SocketAddr::new(host.parse()?, port);
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("MQTT bind {} failed: {:?}", addr, e);
+                return;
+            }
+        };
+        tracing::info!("MQTT: tcp://{}", addr);
+        loop {
+            let (sock, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::warn!("MQTT accept error: {:?}", e);
+                    continue;
+                }
+            };
+            if crate::shutdown::is_shutting_down() {
+                break; // новых не принимаем, порт закрывается
+            }
+            let pool = pool.clone();
+            let hub_state = hub_state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection(sock, peer.ip().to_string(), pool, hub_state).await {
+                    tracing::debug!("MQTT {} closed: {:?}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn authenticate(c: &Connect, pool: &PgPool) -> Option<(UserId, [u8; 32], VerifyingKey)> {
+    let id: UserId = c.username.as_deref()?.parse().ok()?;
+    let token = std::str::from_utf8(c.password.as_deref()?).ok()?;
+    let row: (Vec<u8>, Vec<u8>) = sqlx::query_as(
+        &format!("SELECT public_x, public_ed FROM users WHERE id = $1 AND mqtt_token = $2 AND {}", crate::accounts::ACTIVE_SQL))
+        .bind(id).bind(token_hash(token))
+        .fetch_optional(pool).await.ok()??;
+    let public_x: [u8; 32] = row.0.try_into().ok()?;
+    let public_ed_bytes: [u8; 32] = row.1.try_into().ok()?;
+    Some((id, public_x, VerifyingKey::from_bytes(&public_ed_bytes).ok()?))
+}
+
+async fn connection(sock: TcpStream, ip: String, pool: PgPool, hub_state: Arc<HubState>) -> anyhow::Result<()> {
+    // до CONNECT ответить нечем (CONNACK без CONNECT не бывает) - просто закрываем
+    let _guard = connlimit::connect(&ip).map_err(|r| anyhow::anyhow!("{}", r.reason()))?;
+    let max = max_packet();
+    let (mut rd, mut wr) = sock.into_split();
+
+    // первым пакетом обязан быть CONNECT
+    let connect = match tokio::time::timeout(std::time::Duration::from_secs(10), read_packet(&mut rd, max)).await {
+        Ok(Ok(Some((CONNECT, _, body)))) => parse_connect(&body),
+        _ => None,
+    };
+    let Some(connect) = connect else {
+        anyhow::bail!("no CONNECT");
+    };
+    if !connect.protocol_ok {
+        wr.write_all(&packet(0x20, &[0, BAD_PROTOCOL])).await?;
+        anyhow::bail!("bad protocol");
+    }
+    let Some((id, public_x, public_ed)) = authenticate(&connect, &pool).await else {
+        wr.write_all(&packet(0x20, &[0, NOT_AUTHORIZED])).await?;
+        anyhow::bail!("not authorized: {:?}", connect.username);
+    };
+    if ratelimit::is_banned(&pool, public_ed.as_bytes(), &ip).await {
+        wr.write_all(&packet(0x20, &[0, NOT_AUTHORIZED])).await?;
+        anyhow::bail!("banned: {}", id);
+    }
+    wr.write_all(&packet(0x20, &[0, ACCEPTED])).await?;
+    tracing::debug!("MQTT connected: {} ({}) from {}", id, connect.client_id, ip);
+
+    // writer: сырые байты в сокет; None - закрыть
+    let (wtx, mut wrx) = mpsc::unbounded_channel::<Option<Vec<u8>>>();
+    let (abort_handle, abort_reg) = AbortHandle::new_pair();
+    let reader_abort = abort_handle.clone();
+    tokio::spawn(async move {
+        while let Some(Some(bytes)) = wrx.recv().await {
+            if wr.write_all(&bytes).await.is_err() { break; }
+        }
+        let _ = wr.shutdown().await;
+        reader_abort.abort();
+    });
+
+    // hub -> inbox; до SUBSCRIBE (и после UNSUBSCRIBE) копим, send_to не должен терять молча
+    let (htx, mut hrx) = mpsc::unbounded_channel::<Outgoing>();
+    let (sub_tx, mut sub_rx) = mpsc::unbounded_channel::<bool>();
+    {
+        let wtx = wtx.clone();
+        let topic = inbox_topic(id);
+        tokio::spawn(async move {
+            let mut inbox = Inbox::default();
+            loop {
+                let ready = tokio::select! {
+                    msg = hrx.recv() => match msg {
+                        Some(Outgoing::Binary(b)) => inbox.push(b),
+                        Some(Outgoing::Text(s)) => inbox.push(s.into_bytes()),
+                        Some(Outgoing::Close) | None => { let _ = wtx.send(None); break; }
+                    },
+                    sub = sub_rx.recv() => match sub {
+                        Some(on) => inbox.subscribe(on),
+                        None => break,
+                    },
+                };
+                if ready.into_iter().any(|payload| wtx.send(Some(publish_packet(&topic, &payload))).is_err()) {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut limits = ConnLimits::new(ip.clone());
+    let token = hub_state.add(id, Conn::Channel(htx), abort_handle, ip, public_x, public_ed, Route::Device);
+    if let Some(timeout) = keep_alive_timeout(connect.keep_alive) {
+        hub_state.set_timeout(id, token, timeout);
+    }
+
+    let reader = {
+        let hub_state = hub_state.clone();
+        let wtx = wtx.clone();
+        async move {
+            let telemetry = telemetry_topic(id);
+            let inbox_name = inbox_topic(id);
+            loop {
+                let Ok(Some((kind, flags, body))) = read_packet(&mut rd, max).await else { break; };
+                hub_state.renew_heartbeat(id);
+
+                match kind {
+                    PUBLISH => {
+                        let Some((topic, pid, payload)) = parse_publish(flags, &body) else { break; };
+                        if (flags >> 1) & 0x03 == 2 {
+                            tracing::warn!("MQTT {}: QoS 2 is not supported", id);
+                            break;
+                        }
+                        if topic != telemetry {
+                            tracing::warn!("MQTT {}: publish to foreign topic {}", id, topic);
+                            break;
+                        }
+                        // как 0x10 по WS / TCP: лимит соединения и аккаунта, флуд - бан и разрыв
+                        match limit(id, &public_ed, &pool, &mut limits, Kind::Telemetry).await {
+                            Verdict::Allow => {}
+                            Verdict::Limited => {
+                                if let Some(pid) = pid {
+                                    let _ = wtx.send(Some(packet(0x40, &pid.to_be_bytes()))); // PUBACK: не слать повторно
+                                }
+                                continue;
+                            }
+                            Verdict::Disconnect => break,
+                        }
+                        match serde_json::from_slice(&payload) {
+                            Ok(json) => {
+                                if let Err(e) = insert_data(id, json, &pool, &hub_state).await {
+                                    tracing::warn!("MQTT {}: insert failed: {}", id, e);
+                                }
+                            }
+                            Err(_) => tracing::warn!("MQTT {}: invalid JSON", id),
+                        }
+                        if let Some(pid) = pid {
+                            let _ = wtx.send(Some(packet(0x40, &pid.to_be_bytes()))); // PUBACK
+                        }
+                    }
+                    SUBSCRIBE => {
+                        let Some((pid, topics)) = parse_subscribe(&body, true) else { break; };
+                        let mut ack = pid.to_be_bytes().to_vec();
+                        let mut subscribed = false;
+                        for t in topics {
+                            if t == inbox_name {
+                                subscribed = true;
+                                ack.push(0x00);
+                            } else {
+                                ack.push(0x80); // failure
+                            }
+                        }
+                        let _ = wtx.send(Some(packet(0x90, &ack))); // SUBACK
+                        if subscribed {
+                            let _ = sub_tx.send(true); // накопленное - после SUBACK
+                        }
+                    }
+                    UNSUBSCRIBE => {
+                        let Some((pid, topics)) = parse_subscribe(&body, false) else { break; };
+                        if topics.contains(&inbox_name) {
+                            let _ = sub_tx.send(false);
+                        }
+                        let _ = wtx.send(Some(packet(0xB0, &pid.to_be_bytes()))); // UNSUBACK
+                    }
+                    PINGREQ => { let _ = wtx.send(Some(vec![0xD0, 0])); }
+                    DISCONNECT => break,
+                    _ => {
+                        tracing::warn!("MQTT {}: unexpected packet type {}", id, kind);
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    let _ = Abortable::new(reader, abort_reg).await;
+    let _ = wtx.send(None);
+    hub_state.del(id, token);
+    tracing::debug!("MQTT disconnected: {}", id);
+    Ok(())
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length() {
+        for (len, bytes) in [(0usize, vec![0x00]), (127, vec![0x7F]), (128, vec![0x80, 0x01]), (16_383, vec![0xFF, 0x7F]), (2_097_152, vec![0x80, 0x80, 0x80, 0x01])] {
+            let mut out = Vec::new();
+            encode_len(len, &mut out);
+            assert_eq!(out, bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_packet_roundtrip() {
+        let p = publish_packet("aguardia/7/inbox", &[1, 2, 3]);
+        let mut r: &[u8] = &p;
+        let (kind, flags, body) = read_packet(&mut r, max_packet()).await.unwrap().unwrap();
+        assert_eq!((kind, flags), (PUBLISH, 0));
+        assert_eq!(parse_publish(flags, &body), Some(("aguardia/7/inbox".to_string(), None, vec![1, 2, 3])));
+
+        // длина больше лимита - ошибка до выделения буфера, тело не читаем
+        let big = publish_packet("aguardia/7/telemetry", &[0u8; 100]);
+        let mut r: &[u8] = &big;
+        assert_eq!(read_packet(&mut r, 50).await.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn connect_with_credentials() {
+        // MQTT 3.1.1, clean session + username + password, keepalive 60
+        let mut body = vec![0, 4, b'M', b'Q', b'T', b'T', 4, 0xC2, 0, 60];
+        for s in ["dev-7", "7", "secret"] {
+            body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+            body.extend_from_slice(s.as_bytes());
+        }
+        let c = parse_connect(&body).unwrap();
+        assert!(c.protocol_ok);
+        assert_eq!(c.keep_alive, 60);
+        assert_eq!(c.client_id, "dev-7");
+        assert_eq!(c.username.as_deref(), Some("7"));
+        assert_eq!(c.password.as_deref(), Some(&b"secret"[..]));
+    }
+
+    #[test]
+    fn keep_alive_one_and_a_half() {
+        assert_eq!(keep_alive_timeout(0), None);
+        assert_eq!(keep_alive_timeout(60), Some(Duration::from_secs(90)));
+        assert_eq!(keep_alive_timeout(300), Some(Duration::from_secs(450)));
+    }
+
+    #[test]
+    fn inbox_waits_for_subscribe() {
+        let mut inbox = Inbox::default();
+        assert!(inbox.push(b"1".to_vec()).is_empty());
+        assert!(inbox.push(b"2".to_vec()).is_empty());
+        assert_eq!(inbox.subscribe(true), vec![b"1".to_vec(), b"2".to_vec()]);
+        assert_eq!(inbox.push(b"3".to_vec()), vec![b"3".to_vec()]);
+        assert!(inbox.subscribe(false).is_empty());
+        for i in 0..MAX_PENDING + 5 {
+            inbox.push(vec![i as u8]);
+        }
+        let flushed = inbox.subscribe(true);
+        assert_eq!(flushed.len(), MAX_PENDING);
+        assert_eq!(flushed[0], vec![5u8]); // самые старые выброшены
+    }
+
+    #[test]
+    fn publish_qos1_and_subscribe() {
+        let topic = b"aguardia/7/telemetry";
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic);
+        body.extend_from_slice(&[0x00, 0x05]); // packet id
+        body.extend_from_slice(b"{}");
+        assert_eq!(parse_publish(0x02, &body), Some(("aguardia/7/telemetry".to_string(), Some(5), b"{}".to_vec())));
+
+        let mut sub = vec![0x00, 0x0A];
+        sub.extend_from_slice(&(16u16).to_be_bytes());
+        sub.extend_from_slice(b"aguardia/7/inbox");
+        sub.push(0);
+        assert_eq!(parse_subscribe(&sub, true), Some((10, vec!["aguardia/7/inbox".to_string()])));
+    }
+}
@@ -0,0 +1,36 @@
+use serde_json::Value;
+use sqlx::PgPool;
+
+use std::sync::Arc;
+use crate::email::send_email;
+use crate::hub::{HubState, UserId};
+use crate::server::push;
+
+// уведомить владельца: push во все его онлайн-сессии + письмо, если есть email
+pub async fn notify_user(
+    pool: &PgPool,
+    hub_state: &Arc<HubState>,
+    user_id: UserId,
+    subject: &str,
+    html_body: &str,
+    msg: &Value,
+) {
+    if push(hub_state, user_id, msg).await {
+        tracing::debug!("notify: pushed to {}", user_id);
+    }
+
+    let email = sqlx::query_scalar::<_, Option<String>>("SELECT email FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await;
+
+    match email {
+        Ok(Some(Some(email))) => {
+            if let Err(e) = send_email(&email, subject, html_body).await {
+                tracing::warn!("notify: email to {} failed: {:?}", user_id, e);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("notify: DB error for {}: {:?}", user_id, e),
+    }
+}
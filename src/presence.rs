@@ -0,0 +1,295 @@
+use serde::Serialize;
+use serde_json::{Value, json};
+use sqlx::{PgPool, Row, postgres::PgRow};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use crate::hub::{HubState, UserId};
+use crate::email::escape_html;
+use crate::notify::notify_user;
+
+// события из HubState::add / HubState::del / HubState::renew_heartbeat
+#[derive(Debug)]
+pub enum Presence {
+    Online(UserId, String), // + IP
+    Offline(UserId),
+    Seen(UserId),
+}
+
+// для SELECT ... FROM users LEFT JOIN presence p ON p.user_id = users.id
+pub const PRESENCE_COLUMNS: &str = r#"
+    EXTRACT(EPOCH FROM p.last_seen)::BIGINT AS last_seen,
+    EXTRACT(EPOCH FROM p.last_connect)::BIGINT AS last_connect,
+    EXTRACT(EPOCH FROM p.last_disconnect)::BIGINT AS last_disconnect,
+    p.last_ip
+"#;
+
+#[derive(Debug, Default, Serialize)]
+pub struct PresenceInfo {
+    pub last_seen: Option<i64>,
+    pub last_connect: Option<i64>,
+    pub last_disconnect: Option<i64>,
+    pub last_ip: Option<String>,
+}
+
+impl PresenceInfo {
+    pub fn from_row(row: &PgRow) -> Self {
+        PresenceInfo {
+            last_seen: row.try_get("last_seen").unwrap_or(None),
+            last_connect: row.try_get("last_connect").unwrap_or(None),
+            last_disconnect: row.try_get("last_disconnect").unwrap_or(None),
+            last_ip: row.try_get("last_ip").unwrap_or(None),
+        }
+    }
+}
+
+pub fn presence_json(row: &PgRow) -> Value {
+    json!(PresenceInfo::from_row(row))
+}
+
+pub fn start(pool: PgPool, hub_state: Arc<HubState>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        hub_state.set_presence(tx);
+        run(pool, hub_state, rx).await;
+    });
+}
+
+// debounce offline-уведомлений: без БД, чтобы переходы проверялись тестами
+// offline_since - кто сейчас offline; reported - (device_id, watcher), кому уже сказали "offline"
+#[derive(Default)]
+struct Debounce {
+    offline_since: HashMap<UserId, Instant>,
+    reported: HashSet<(UserId, UserId)>,
+}
+
+// device_id -> [(watcher, offline_minutes)]
+type Watches = HashMap<UserId, Vec<(UserId, i32)>>;
+
+impl Debounce {
+    fn offline(&mut self, id: UserId, now: Instant) {
+        self.offline_since.insert(id, now);
+    }
+
+    // вернулся: "online" только тем, кому успели сказать "offline"
+    fn online(&mut self, id: UserId, now: Instant) -> Option<(u64, Vec<UserId>)> {
+        let since = self.offline_since.remove(&id);
+        let watchers: Vec<UserId> = self.reported.iter().filter(|(d, _)| *d == id).map(|(_, w)| *w).collect();
+        if watchers.is_empty() {
+            return None;
+        }
+        self.reported.retain(|(d, _)| *d != id);
+        let offline_sec = since.map(|t| now.saturating_duration_since(t).as_secs()).unwrap_or(0);
+        Some((offline_sec, watchers))
+    }
+
+    // кого спросить в device_watch на этом тике
+    fn pending(&self) -> Vec<UserId> {
+        self.offline_since.keys().copied().collect()
+    }
+
+    // [(device_id, offline_sec, кому сказать "offline")]; устройства без наблюдателей больше не проверяем
+    fn tick(&mut self, watches: &Watches, now: Instant) -> Vec<(UserId, u64, Vec<UserId>)> {
+        let mut events = Vec::new();
+        self.offline_since.retain(|id, since| {
+            let Some(watchers) = watches.get(id) else {
+                return false;
+            };
+            let offline_sec = now.saturating_duration_since(*since).as_secs();
+            let due: Vec<UserId> = watchers.iter()
+                .filter(|(w, minutes)| offline_sec >= (*minutes).max(0) as u64 * 60 && !self.reported.contains(&(*id, *w)))
+                .map(|(w, _)| *w)
+                .collect();
+            if !due.is_empty() {
+                self.reported.extend(due.iter().map(|w| (*id, *w)));
+                events.push((*id, offline_sec, due));
+            }
+            true
+        });
+        events
+    }
+}
+
+async fn run(pool: PgPool, hub_state: Arc<HubState>, mut rx: mpsc::UnboundedReceiver<Presence>) {
+    let mut debounce = Debounce::default();
+    let mut seen: HashSet<UserId> = HashSet::new(); // last_seen пишем пачкой раз в тик
+    let mut ticker = tokio::time::interval(Duration::from_secs(10));
+
+    loop {
+        tokio::select! {
+            ev = rx.recv() => match ev {
+                None => break,
+                Some(Presence::Seen(id)) => { seen.insert(id); }
+                Some(Presence::Offline(id)) => {
+                    seen.remove(&id);
+                    debounce.offline(id, Instant::now());
+                    webhook(&pool, id, "device_offline");
+                    if let Err(e) = sqlx::query(
+                        "UPDATE presence SET last_seen = now(), last_disconnect = now() WHERE user_id = $1")
+                        .bind(id).execute(&pool).await
+                    {
+                        tracing::warn!("presence: DB error for {}: {:?}", id, e);
+                    }
+                }
+                Some(Presence::Online(id, ip)) => {
+                    webhook(&pool, id, "device_online");
+                    if let Err(e) = sqlx::query(
+                        r#"INSERT INTO presence (user_id, last_connect, last_seen, last_ip) VALUES ($1, now(), now(), $2)
+                           ON CONFLICT (user_id) DO UPDATE SET last_connect = now(), last_seen = now(), last_ip = EXCLUDED.last_ip"#)
+                        .bind(id).bind(&ip).execute(&pool).await
+                    {
+                        tracing::warn!("presence: DB error for {}: {:?}", id, e);
+                    }
+                    if let Some((offline_sec, watchers)) = debounce.online(id, Instant::now()) {
+                        device_event(&pool, &hub_state, id, "online", offline_sec, &watchers).await;
+                    }
+                }
+            },
+
+            _ = ticker.tick() => {
+                if !seen.is_empty() {
+                    let ids: Vec<UserId> = seen.drain().collect();
+                    if let Err(e) = sqlx::query("UPDATE presence SET last_seen = now() WHERE user_id = ANY($1)")
+                        .bind(&ids).execute(&pool).await
+                    {
+                        tracing::warn!("presence: DB error updating last_seen: {:?}", e);
+                    }
+                }
+
+                let ids = debounce.pending();
+                if ids.is_empty() {
+                    continue;
+                }
+                // наблюдатели всех offline устройств одним запросом
+                let watches = match sqlx::query_as::<_, (UserId, UserId, i32)>(
+                    "SELECT device_id, user_id, offline_minutes FROM device_watch WHERE device_id = ANY($1)")
+                    .bind(&ids).fetch_all(&pool).await
+                {
+                    Ok(rows) => rows.into_iter().fold(Watches::new(), |mut m, (d, w, minutes)| {
+                        m.entry(d).or_default().push((w, minutes));
+                        m
+                    }),
+                    Err(e) => {
+                        tracing::warn!("presence: DB error loading watchers: {:?}", e);
+                        continue;
+                    }
+                };
+                for (id, offline_sec, due) in debounce.tick(&watches, Instant::now()) {
+                    device_event(&pool, &hub_state, id, "offline", offline_sec, &due).await;
+                }
+            }
+        }
+    }
+}
+
+// webhooks получают каждый переход без задержки
+fn webhook(pool: &PgPool, device_id: UserId, event: &'static str) {
+    let pool = pool.clone();
+    crate::shutdown::spawn(async move { crate::webhooks::dispatch(&pool, device_id, event, json!({})).await });
+}
+
+async fn device_event(
+    pool: &PgPool,
+    hub_state: &Arc<HubState>,
+    device_id: UserId,
+    kind: &str,
+    offline_sec: u64,
+    watchers: &[UserId],
+) {
+    let details = json!({ "offline_sec": offline_sec });
+    if let Err(e) = sqlx::query("INSERT INTO device_events (device_id, kind, details) VALUES ($1, $2, $3)")
+        .bind(device_id).bind(kind).bind(&details).execute(pool).await
+    {
+        tracing::warn!("presence: DB error saving event for {}: {:?}", device_id, e);
+    }
+    tracing::info!("device {} {} (offline {} sec)", device_id, kind, offline_sec);
+
+    let name = device_name(pool, device_id).await;
+    let msg = json!({
+        "action": format!("device_{}", kind),
+        "device_id": device_id,
+        "name": name,
+        "offline_sec": offline_sec,
+        "time": crate::crypto25519::get_unixtime(),
+    });
+    let html_name = escape_html(&name);
+    let (subject, html) = if kind == "offline" {
+        (
+            format!("Aguardia: device {} is offline", name),
+            format!("<p>Device <b>{}</b> (#{}) has been offline for {} min.</p>", html_name, device_id, offline_sec / 60),
+        )
+    } else {
+        (
+            format!("Aguardia: device {} is back online", name),
+            format!("<p>Device <b>{}</b> (#{}) is back online after {} min.</p>", html_name, device_id, offline_sec / 60),
+        )
+    };
+
+    for w in watchers {
+        notify_user(pool, hub_state, *w, &subject, &html, &msg).await;
+    }
+}
+
+async fn device_name(pool: &PgPool, device_id: UserId) -> String {
+    sqlx::query_scalar::<_, Option<Value>>("SELECT info FROM users WHERE id = $1")
+        .bind(device_id)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .flatten()
+        .and_then(|info| info.get("name").and_then(|v| v.as_str()).map(String::from))
+        .unwrap_or_else(|| device_id.to_string())
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn offline_reported_once_after_delay() {
+        let t0 = Instant::now();
+        let watches: Watches = HashMap::from([(5, vec![(1, 2), (2, 10)])]);
+        let mut d = Debounce::default();
+        d.offline(5, t0);
+
+        assert!(d.tick(&watches, t0 + MIN).is_empty()); // меньше 2 минут
+        assert_eq!(d.tick(&watches, t0 + 2 * MIN), vec![(5, 120, vec![1])]);
+        assert!(d.tick(&watches, t0 + 3 * MIN).is_empty()); // watcher 1 уже знает
+        assert_eq!(d.tick(&watches, t0 + 10 * MIN), vec![(5, 600, vec![2])]);
+        assert!(d.tick(&watches, t0 + 11 * MIN).is_empty());
+
+        let (sec, mut watchers) = d.online(5, t0 + 12 * MIN).unwrap();
+        watchers.sort();
+        assert_eq!((sec, watchers), (720, vec![1, 2]));
+        assert!(d.pending().is_empty() && d.reported.is_empty());
+    }
+
+    #[test]
+    fn short_blip_is_silent() {
+        let t0 = Instant::now();
+        let watches: Watches = HashMap::from([(5, vec![(1, 2)])]);
+        let mut d = Debounce::default();
+        d.offline(5, t0);
+        assert!(d.tick(&watches, t0 + MIN).is_empty());
+        assert_eq!(d.online(5, t0 + MIN), None); // "offline" не отправляли - и "online" не нужен
+        assert!(d.tick(&watches, t0 + 5 * MIN).is_empty());
+    }
+
+    #[test]
+    fn unwatched_device_dropped() {
+        let t0 = Instant::now();
+        let mut d = Debounce::default();
+        d.offline(5, t0);
+        d.offline(6, t0);
+        let watches: Watches = HashMap::from([(6, vec![(1, 0)])]);
+        assert_eq!(d.tick(&watches, t0), vec![(6, 0, vec![1])]);
+        assert_eq!(d.pending(), vec![6]);
+    }
+}
@@ -0,0 +1,404 @@
+// Протокол cmd 0x00: запрос {"action":"...", ...} -> Request, ответ - типизированные структуры ниже,
+// ошибка -> {"error":"<code>","message":"..."} (code стабилен, по нему и ветвиться клиенту; message - для людей).
+// Новое действие: вариант в Request + ветка в server_0x00::actions (match полный, забыть нельзя).
+
+use hex::FromHex;
+use serde::{Deserialize, Deserializer, Serialize, de};
+use serde_json::{Map, Value, json};
+use sqlx::{Row, postgres::PgRow};
+use crate::hub::UserId;
+use crate::presence::PresenceInfo;
+use crate::accounts::AccountStatus;
+
+// ================ ПОЛЯ ================
+
+// 32 байта ключа в hex (X25519 или Ed25519)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key(pub [u8; 32]);
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        <[u8; 32]>::from_hex(&s).map(Key).map_err(|_| de::Error::custom("bad key: expected 64 hex chars"))
+    }
+}
+
+// unixtime: число или строка с числом ("time_from":"0" от старых клиентов)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp(pub i64);
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl de::Visitor<'_> for Visitor {
+            type Value = Timestamp;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("unixtime as number or string")
+            }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Timestamp, E> {
+                Ok(Timestamp(v))
+            }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Timestamp, E> {
+                Ok(Timestamp(v.min(i64::MAX as u64) as i64))
+            }
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Timestamp, E> {
+                Ok(Timestamp(v as i64))
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Timestamp, E> {
+                v.trim().parse::<i64>().map(Timestamp).map_err(|_| E::custom(format!("bad unixtime: {:?}", v)))
+            }
+        }
+        d.deserialize_any(Visitor)
+    }
+}
+
+// владелец чужого устройства доказывает владение его ключами
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OwnerKeys {
+    pub x: Option<Key>,
+    pub ed: Option<Key>,
+}
+
+// {"offset":0,"limit":50} - limit не больше 1000
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Page {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl Page {
+    pub fn get(&self) -> (i64, i64) {
+        (self.offset.unwrap_or(0).max(0), self.limit.unwrap_or(50).clamp(1, 1000))
+    }
+}
+
+// ================ ЗАПРОС ================
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    MyId,
+    GetId { x: Key, ed: Key },
+    IsOnline { user_id: UserId, x: Key, ed: Key, #[serde(default)] details: bool },
+    SendTo { user_id: UserId, x: Key, ed: Key, body: String },
+    MyInfo,
+    MyPermissions,
+    MyDevices,
+    UpdateMyInfo { info: Value },
+    CreateNewDevice { name: String, x: Key, ed: Key, owner: Option<UserId> },
+    DeleteDevice { device_id: UserId, #[serde(flatten)] keys: OwnerKeys },
+    ReadData { device_id: UserId, time_from: Option<Timestamp>, time_to: Option<Timestamp>, #[serde(flatten)] keys: OwnerKeys },
+    SubscribeData { device_id: UserId, #[serde(flatten)] keys: OwnerKeys },
+    UnsubscribeData { device_id: UserId },
+    AddAlert {
+        device_id: UserId,
+        path: String,
+        op: String,
+        threshold: f64,
+        hysteresis: Option<f64>,
+        cooldown: Option<i64>,
+        name: Option<String>,
+        #[serde(flatten)] keys: OwnerKeys,
+    },
+    ListAlerts { device_id: UserId, #[serde(flatten)] keys: OwnerKeys },
+    DeleteAlert { alert_id: i32 },
+    WatchDevice { device_id: UserId, minutes: Option<i64>, #[serde(flatten)] keys: OwnerKeys },
+    UnwatchDevice { device_id: UserId },
+    DeviceEvents { device_id: UserId, limit: Option<i64>, #[serde(flatten)] keys: OwnerKeys },
+    AddWebhook { device_id: UserId, url: String, secret: Option<String>, #[serde(flatten)] keys: OwnerKeys },
+    ListWebhooks { device_id: UserId, #[serde(flatten)] keys: OwnerKeys },
+    DeleteWebhook { webhook_id: i32 },
+    WebhookDeliveries { webhook_id: i32, limit: Option<i64> },
+    SetMqttToken { device_id: UserId },
+    DeleteData { data_id: i64, device_id: UserId, #[serde(flatten)] keys: OwnerKeys },
+    ReloadConfig,
+
+    // admin
+    AdminUsers { search: Option<String>, kind: Option<String>, status: Option<String>, #[serde(flatten)] page: Page },
+    AdminUser { user_id: UserId },
+    AdminSetInfo { user_id: UserId, admin_info: Map<String, Value> },
+    AdminSessions { #[serde(flatten)] page: Page },
+    AdminKick { user_id: UserId, reason: Option<String> },
+    SuspendUser { user_id: UserId, status: Option<String>, reason: Option<String>, until: Option<Timestamp> },
+    UnsuspendUser { user_id: UserId },
+    AdminDataVolume { device_id: Option<UserId>, #[serde(flatten)] page: Page },
+
+    // roles
+    ListRoles,
+    SetRole { role: String, permissions: Vec<String>, info: Option<String> },
+    GrantRole { user_id: UserId, role: String },
+    RevokeRole { user_id: UserId, role: String },
+
+    AuditLog { actor: Option<UserId>, target: Option<UserId>, event: Option<String>, since: Option<Timestamp>, limit: Option<i64> },
+
+    #[serde(other)]
+    Unknown,
+}
+
+// ================ ОШИБКИ ================
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    BadJson,
+    BadRequest(String), // нет поля, не тот тип, недопустимое значение
+    UnknownAction,
+    AccessDenied,
+    NotFound(&'static str), // что именно: "user", "role"
+    AlreadyExists,
+    Offline,
+    Undelivered,
+    Config(String),
+    Db(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BadJson => "bad_json",
+            Error::BadRequest(_) => "bad_request",
+            Error::UnknownAction => "unknown_action",
+            Error::AccessDenied => "access_denied",
+            Error::NotFound(_) => "not_found",
+            Error::AlreadyExists => "already_exists",
+            Error::Offline => "offline",
+            Error::Undelivered => "send_error",
+            Error::Config(_) => "config_error",
+            Error::Db(_) => "db_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::BadJson => "invalid JSON".into(),
+            Error::BadRequest(m) | Error::Config(m) => m.clone(),
+            Error::UnknownAction => "unknown action".into(),
+            Error::AccessDenied => "access denied".into(),
+            Error::NotFound(what) => format!("{} not found", what),
+            Error::AlreadyExists => "already exists".into(),
+            Error::Offline => "offline".into(),
+            Error::Undelivered => "send failed".into(),
+            Error::Db(e) => format!("DB err: {}", e),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "error": self.code(), "message": self.message() })
+    }
+
+    pub fn bad(m: impl Into<String>) -> Self {
+        Error::BadRequest(m.into())
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Db(e.to_string())
+    }
+}
+
+// текст запроса -> JSON (для audit по action, даже если сам запрос не разобрался)
+pub fn parse_json(text: &str) -> Result<Value, Error> {
+    serde_json::from_str(text).map_err(|_| Error::BadJson)
+}
+
+pub fn request(json: &Value) -> Result<Request, Error> {
+    Request::deserialize(json).map_err(|e| Error::BadRequest(e.to_string()))
+}
+
+// ================ ОТВЕТЫ ================
+
+#[derive(Debug, Serialize)]
+pub struct Paged<T> {
+    pub total: i64,
+    pub items: Vec<T>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OnlineDetails {
+    #[serde(flatten)]
+    pub presence: PresenceInfo,
+    pub online: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MyInfo {
+    #[serde(flatten)]
+    pub presence: PresenceInfo,
+    pub info: Value,
+    pub time_reg: i64,
+    pub time_upd: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Device {
+    pub id: UserId,
+    #[serde(flatten)]
+    pub presence: PresenceInfo,
+    pub info: Value,
+    pub time_reg: i64,
+    pub online: bool,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DataRecord {
+    pub id: i64,
+    pub time: i64,
+    pub payload: Value,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DeviceEvent {
+    pub id: i64,
+    pub kind: String,
+    pub time: i64,
+    pub details: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookCreated {
+    pub id: i32,
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub active: bool,
+    pub time_reg: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Delivery {
+    pub id: i64,
+    pub event: String,
+    pub attempts: i32,
+    pub ok: bool,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub time: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MqttCredentials {
+    pub username: String,
+    pub password: String,
+    pub telemetry: String,
+    pub inbox: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUserItem {
+    pub id: UserId,
+    pub kind: &'static str, // "user" (есть email) или "device"
+    pub email: Option<String>,
+    pub info: Value,
+    pub created_by: Option<Value>,
+    pub time_reg: i64,
+    pub online: bool,
+    #[serde(flatten)]
+    pub presence: PresenceInfo,
+    #[serde(flatten)]
+    pub status: AccountStatus,
+}
+
+impl AdminUserItem {
+    pub fn from_row(row: &PgRow, online: bool) -> Self {
+        let email = row.try_get::<Option<String>, _>("email").unwrap_or(None);
+        AdminUserItem {
+            id: row.try_get("id").unwrap_or(0),
+            kind: if email.is_some() { "user" } else { "device" },
+            email,
+            info: row.try_get("info").unwrap_or(Value::Null),
+            created_by: row.try_get("created_by").unwrap_or(None),
+            time_reg: row.try_get("time_reg").unwrap_or(0),
+            online,
+            presence: PresenceInfo::from_row(row),
+            status: AccountStatus::from_row(row),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUser {
+    pub id: UserId,
+    pub email: Option<String>,
+    pub info: Value,
+    pub admin_info: Value,
+    pub time_reg: i64,
+    pub time_upd: i64,
+    pub node_id: Option<String>,
+    pub online: bool,
+    #[serde(flatten)]
+    pub presence: PresenceInfo,
+    #[serde(flatten)]
+    pub status: AccountStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Kicked {
+    pub kicked: bool,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DataVolume {
+    pub device_id: UserId,
+    pub records: i64,
+    pub bytes: i64,
+    pub first: i64,
+    pub last: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub info: String,
+    pub users: Vec<UserId>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub time: i64,
+    pub actor: Option<UserId>,
+    pub target: Option<UserId>,
+    pub action: String,
+    pub ip: Option<String>,
+    pub result: String,
+    pub details: Value,
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Request, Error> {
+        request(&parse_json(text)?)
+    }
+
+    #[test]
+    fn requests() {
+        let key = "AA".repeat(32);
+        let r = parse(&format!(r#"{{"action":"read_data","device_id":5,"time_from":"10","time_to":20,"x":"{}","ed":"{}"}}"#, key, key)).unwrap();
+        match r {
+            Request::ReadData { device_id, time_from, time_to, keys } => {
+                assert_eq!((device_id, time_from, time_to), (5, Some(Timestamp(10)), Some(Timestamp(20))));
+                assert_eq!(keys.x, Some(Key([0xAA; 32])));
+            }
+            r => panic!("{:?}", r),
+        }
+
+        assert!(matches!(parse(r#"{"action":"read_data","device_id":5}"#), Ok(Request::ReadData { time_from: None, .. })));
+        assert!(matches!(parse(r#"{"action":"status"}"#), Ok(Request::Status)));
+        assert!(matches!(parse(r#"{"action":"nope"}"#), Ok(Request::Unknown)));
+        assert_eq!(parse("{").unwrap_err(), Error::BadJson);
+        assert_eq!(parse(r#"{"action":"read_data"}"#).unwrap_err().code(), "bad_request");
+        assert_eq!(parse(r#"{"action":"read_data","device_id":5,"time_from":"x"}"#).unwrap_err().code(), "bad_request");
+        assert_eq!(parse(r#"{"action":"get_id","x":"00","ed":"00"}"#).unwrap_err().code(), "bad_request");
+        assert_eq!(Error::AccessDenied.to_json(), json!({ "error": "access_denied", "message": "access denied" }));
+    }
+}
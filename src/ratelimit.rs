@@ -0,0 +1,248 @@
+// token bucket-ы: udp (по IP), защита от флуда на соединениях (по соединению и по аккаунту), баны
+
+use ed25519_dalek::VerifyingKey;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use crate::config::CONFIG;
+use crate::hub::UserId;
+
+// token bucket: rate токенов в секунду, не больше burst
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: f64, now: Instant) -> Self {
+        Self { tokens: burst, last: now }
+    }
+
+    pub fn take(&mut self, rate: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // полностью восстановился - можно выкинуть из памяти
+    fn full(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * rate >= burst
+    }
+}
+
+// набор bucket-ов по ключу (IP, UserId, ...)
+pub struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<K, TokenBucket>,
+    last_prune: Instant,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst: burst.max(1.0), buckets: HashMap::new(), last_prune: Instant::now() }
+    }
+
+    pub fn check(&mut self, key: K, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_prune) > Duration::from_secs(60) {
+            self.prune(now);
+        }
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.entry(key).or_insert_with(|| TokenBucket::new(burst, now)).take(rate, burst, now)
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, b| !b.full(rate, burst, now));
+        self.last_prune = now;
+    }
+}
+
+// === флуд на соединениях ===
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Relay,     // пакет другому адресату
+    Command,   // 0x00 и прочее серверу
+    Telemetry, // 0x10, insert в БД
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Relay, Kind::Command, Kind::Telemetry];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Relay => "relay",
+            Kind::Command => "command",
+            Kind::Telemetry => "telemetry",
+        }
+    }
+
+    // (rate, burst) на одно соединение
+    fn limits(self) -> (f64, f64) {
+        match self {
+            Kind::Relay => (CONFIG.rate_relay_per_sec, CONFIG.rate_relay_burst),
+            Kind::Command => (CONFIG.rate_command_per_sec, CONFIG.rate_command_burst),
+            Kind::Telemetry => (CONFIG.rate_telemetry_per_sec, CONFIG.rate_telemetry_burst),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Limited,    // ответить ошибкой, соединение живет
+    Disconnect, // нарушений слишком много - закрыть и забанить
+}
+
+// аккаунт = все его соединения вместе (WS, TCP, HTTP, MQTT, UDP), лимит в rate_account_factor раз больше
+static ACCOUNTS: LazyLock<[Mutex<RateLimiter<UserId>>; 3]> = LazyLock::new(|| {
+    Kind::ALL.map(|k| {
+        let (rate, burst) = k.limits();
+        Mutex::new(RateLimiter::new(rate * CONFIG.rate_account_factor, burst * CONFIG.rate_account_factor))
+    })
+});
+
+pub fn account_check(user_id: UserId, kind: Kind) -> bool {
+    ACCOUNTS[kind as usize].lock().unwrap().check(user_id, Instant::now())
+}
+
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+// живет в цикле чтения соединения
+pub struct ConnLimits {
+    pub ip: String,
+    buckets: [TokenBucket; 3],
+    violations: u32,
+    window_start: Instant,
+}
+
+impl ConnLimits {
+    pub fn new(ip: String) -> Self {
+        let now = Instant::now();
+        Self {
+            ip,
+            buckets: Kind::ALL.map(|k| TokenBucket::new(k.limits().1.max(1.0), now)),
+            violations: 0,
+            window_start: now,
+        }
+    }
+
+    pub fn check(&mut self, user_id: UserId, kind: Kind) -> Verdict {
+        self.check_at(kind, Instant::now(), || account_check(user_id, kind))
+    }
+
+    fn check_at(&mut self, kind: Kind, now: Instant, account: impl FnOnce() -> bool) -> Verdict {
+        let (rate, burst) = kind.limits();
+        if self.buckets[kind as usize].take(rate, burst.max(1.0), now) && account() {
+            return Verdict::Allow;
+        }
+        crate::metrics::inc(&crate::metrics::RATE_LIMITED);
+        self.violation_at(now)
+    }
+
+    // мусор, который не прошел проверку подписи, тоже нарушение - иначе им можно грузить verify бесконечно
+    pub fn violation(&mut self) -> Verdict {
+        self.violation_at(Instant::now())
+    }
+
+    fn violation_at(&mut self, now: Instant) -> Verdict {
+        if now.saturating_duration_since(self.window_start) > VIOLATION_WINDOW {
+            self.window_start = now;
+            self.violations = 0;
+        }
+        self.violations += 1;
+        if self.violations > CONFIG.rate_violations_max { Verdict::Disconnect } else { Verdict::Limited }
+    }
+}
+
+// ответ на превышение: {"error":"rate_limited","kind":"telemetry"}
+pub fn limited_json(kind: Kind) -> Value {
+    json!({ "error": "rate_limited", "kind": kind.as_str() })
+}
+
+pub fn ban_reason() -> String {
+    format!("rate limit exceeded, banned for {} s", CONFIG.ban_sec)
+}
+
+// временный бан ключа (и IP, если ban_ip)
+pub async fn ban(pool: &PgPool, user_id: UserId, public_ed: &VerifyingKey, ip: &str, reason: &str) {
+    crate::metrics::inc(&crate::metrics::BANS);
+    tracing::warn!("Ban {} ip={} for {} s: {}", user_id, ip, CONFIG.ban_sec, reason);
+    crate::audit::write(pool, crate::audit::Entry {
+        actor: Some(user_id), target: Some(user_id), action: "ban".into(), ip: Some(ip.to_string()),
+        result: crate::audit::OK, details: json!({ "reason": reason, "sec": CONFIG.ban_sec, "ban_ip": CONFIG.ban_ip }),
+    });
+    let ip = if CONFIG.ban_ip && !ip.is_empty() { Some(ip) } else { None };
+    if let Err(e) = sqlx::query(
+        "INSERT INTO bans (user_id, public_ed, ip, reason, until) VALUES ($1, $2, $3, $4, now() + make_interval(secs => $5))")
+        .bind(user_id)
+        .bind(&public_ed.to_bytes()[..])
+        .bind(ip)
+        .bind(reason)
+        .bind(CONFIG.ban_sec as f64)
+        .execute(pool).await
+    {
+        tracing::error!("Ban insert failed: {:?}", e);
+    }
+}
+
+pub async fn is_banned(pool: &PgPool, public_ed: &[u8], ip: &str) -> bool {
+    sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM bans WHERE until > now() AND (public_ed = $1 OR ip = $2))")
+        .bind(public_ed)
+        .bind(ip)
+        .fetch_one(pool).await
+        .unwrap_or_else(|e| {
+            tracing::error!("Ban check failed: {:?}", e);
+            false
+        })
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let t0 = Instant::now();
+        let mut rl = RateLimiter::new(2.0, 3.0);
+        assert!(rl.check("a", t0));
+        assert!(rl.check("a", t0));
+        assert!(rl.check("a", t0));
+        assert!(!rl.check("a", t0));
+        assert!(rl.check("b", t0)); // у другого ключа свой bucket
+
+        assert!(rl.check("a", t0 + Duration::from_millis(500)));
+        assert!(!rl.check("a", t0 + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn violations_escalate() {
+        let t0 = Instant::now();
+        let mut c = ConnLimits::new(String::new());
+        let burst = Kind::Command.limits().1 as u32;
+        for _ in 0..burst {
+            assert_eq!(c.check_at(Kind::Command, t0, || true), Verdict::Allow);
+        }
+        for _ in 0..CONFIG.rate_violations_max {
+            assert_eq!(c.check_at(Kind::Command, t0, || true), Verdict::Limited);
+        }
+        assert_eq!(c.check_at(Kind::Command, t0, || true), Verdict::Disconnect);
+
+        // свой bucket у каждого вида; аккаунтный лимит тоже считается
+        assert_eq!(c.check_at(Kind::Relay, t0, || true), Verdict::Allow);
+        assert_eq!(c.check_at(Kind::Relay, t0 + VIOLATION_WINDOW * 2, || false), Verdict::Limited);
+    }
+}
@@ -0,0 +1,155 @@
+// Права из БД: roles(name, permissions[]) + user_roles(user_id, role).
+// config admins - bootstrap-суперпользователи: у них все права, даже если таблицы пустые.
+// Права читаются на каждый 0x00 запрос (один индексный SELECT), так grant/revoke сразу видны на всех узлах кластера.
+
+use serde::Serialize;
+use sqlx::PgPool;
+use crate::hub::UserId;
+use crate::protocol::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Perm {
+    ReadAny,    // читать данные / алерты / события / вебхуки чужих устройств
+    WriteAny,   // менять и удалять чужие устройства, их данные, алерты, вебхуки
+    Provision,  // create_new_device для другого владельца (provisioned_by: потом и set_mqtt_token им)
+    UsersRead,  // admin_users, admin_user, admin_sessions, admin_data_volume
+    UsersWrite, // admin_set_info, admin_kick, suspend_user, unsuspend_user
+    AuditRead,  // audit_log
+    Config,     // reload_config
+    Roles,      // set_role, grant_role, revoke_role
+}
+
+impl Perm {
+    pub const ALL: [Perm; 8] = [
+        Perm::ReadAny, Perm::WriteAny, Perm::Provision, Perm::UsersRead,
+        Perm::UsersWrite, Perm::AuditRead, Perm::Config, Perm::Roles,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Perm::ReadAny => "read_any",
+            Perm::WriteAny => "write_any",
+            Perm::Provision => "provision",
+            Perm::UsersRead => "users_read",
+            Perm::UsersWrite => "users_write",
+            Perm::AuditRead => "audit_read",
+            Perm::Config => "config",
+            Perm::Roles => "roles",
+        }
+    }
+
+    // "*" тоже допустимо в roles.permissions
+    pub fn is_valid(name: &str) -> bool {
+        name == "*" || Perm::ALL.iter().any(|p| p.as_str() == name)
+    }
+}
+
+// ответ my_permissions
+#[derive(Debug, Serialize)]
+pub struct PermsInfo {
+    pub superuser: bool,
+    pub roles: Vec<String>,
+    pub permissions: Vec<&'static str>,
+}
+
+#[derive(Debug, Default)]
+pub struct Perms {
+    superuser: bool,      // из config admins
+    roles: Vec<String>,
+    granted: Vec<String>, // объединение permissions всех ролей
+}
+
+impl Perms {
+    pub fn has(&self, p: Perm) -> bool {
+        self.superuser || self.granted.iter().any(|g| g == "*" || g == p.as_str())
+    }
+
+    // хоть какие-то привилегии: для audit_log ("as_admin")
+    pub fn any(&self) -> bool {
+        self.superuser || !self.granted.is_empty()
+    }
+
+    pub fn is_superuser(&self) -> bool {
+        self.superuser
+    }
+
+    // все права other есть и у self: нельзя трогать тех, у кого прав больше, чем у тебя
+    pub fn covers(&self, other: &Perms) -> bool {
+        (self.superuser || !other.superuser) && Perm::ALL.iter().all(|p| !other.has(*p) || self.has(*p))
+    }
+
+    pub fn info(&self) -> PermsInfo {
+        PermsInfo {
+            superuser: self.superuser,
+            roles: self.roles.clone(),
+            permissions: Perm::ALL.iter().filter(|p| self.has(**p)).map(|p| p.as_str()).collect(),
+        }
+    }
+}
+
+pub async fn load(pool: &PgPool, user_id: UserId) -> Result<Perms, sqlx::Error> {
+    let superuser = crate::config::runtime().admins.contains(&user_id);
+    let rows = sqlx::query_as::<_, (String, Vec<String>)>(
+        "SELECT r.name, r.permissions FROM user_roles u JOIN roles r ON r.name = u.role WHERE u.user_id = $1")
+        .bind(user_id)
+        .fetch_all(pool).await?;
+
+    let mut perms = Perms { superuser, ..Default::default() };
+    for (role, permissions) in rows {
+        perms.roles.push(role);
+        perms.granted.extend(permissions);
+    }
+    Ok(perms)
+}
+
+// grant_role (0x00) и `admin grant` (CLI); by = None - из CLI
+pub async fn grant(pool: &PgPool, user_id: UserId, role: &str, by: Option<UserId>) -> Result<(), Error> {
+    let exists = sqlx::query_scalar::<_, i32>("SELECT 1 FROM roles WHERE name = $1")
+        .bind(role).fetch_optional(pool).await?;
+    if exists.is_none() {
+        return Err(Error::NotFound("role"));
+    }
+    sqlx::query(r#"INSERT INTO user_roles (user_id, role, granted_by) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING"#)
+        .bind(user_id).bind(role).bind(by)
+        .execute(pool).await.map_err(|e| match e {
+            sqlx::Error::Database(ref d) if d.code().as_deref() == Some("23503") => Error::NotFound("user"),
+            e => e.into(),
+        })?;
+    Ok(())
+}
+
+// false - такой роли у него не было
+pub async fn revoke(pool: &PgPool, user_id: UserId, role: &str) -> Result<bool, sqlx::Error> {
+    let done = sqlx::query(r#"DELETE FROM user_roles WHERE user_id = $1 AND role = $2"#)
+        .bind(user_id).bind(role)
+        .execute(pool).await?;
+    Ok(done.rows_affected() > 0)
+}
+
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perms_from_roles() {
+        let support = Perms { granted: vec!["read_any".into(), "users_read".into()], ..Default::default() };
+        assert!(support.has(Perm::ReadAny));
+        assert!(!support.has(Perm::WriteAny));
+        assert!(support.any());
+
+        let admin = Perms { granted: vec!["*".into()], ..Default::default() };
+        assert!(Perm::ALL.iter().all(|p| admin.has(*p)));
+
+        let root = Perms { superuser: true, ..Default::default() };
+        assert!(root.has(Perm::Roles));
+        assert!(!Perms::default().any());
+
+        assert!(Perm::is_valid("*") && Perm::is_valid("audit_read") && !Perm::is_valid("root"));
+
+        assert!(admin.covers(&support) && !support.covers(&admin));
+        assert!(support.covers(&Perms::default()) && support.covers(&support));
+        assert!(!admin.covers(&root) && root.covers(&admin));
+    }
+}
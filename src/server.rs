@@ -4,8 +4,14 @@ use sqlx::PgPool;
 
 
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::hub::HubState;
+use std::time::Duration;
+use tracing::Instrument;
+use ed25519_dalek::VerifyingKey;
+use crate::crypto25519::{self, DecryptError};
+use crate::hub::{HubState, UserId, Outgoing, send_to};
+use crate::audit;
+use crate::ratelimit::{self, ConnLimits, Kind, Verdict};
+use crate::{MY_CONFIG, config::CONFIG, metrics};
 
 // use crate::hub;
 // use sqlx::Row;
@@ -23,18 +29,260 @@ fn ok1(v: serde_json::Value) -> Vec<u8> {
     serde_json::to_vec(&wrapped).unwrap()
 }
 
-pub async fn server(cmd: u8, user_id: i32, body: &[u8], pool: &PgPool, hub_state: &Arc<RwLock<HubState>>) -> Vec<u8> {
+// random message id for server-originated frames, 0 is reserved
+pub fn new_message_id() -> u16 {
+    rand::random::<u16>().max(1)
+}
+
+// [from=0 u32 LE][encrypt_and_sign([id u16 LE][cmd u8][body])]
+pub fn server_frame(message_id: u16, cmd: u8, body: &[u8], to_x: &[u8; 32]) -> Vec<u8> {
+    let from = 0u32; // сервер=0
+
+    let mut inner = Vec::with_capacity(3 + body.len());
+    inner.extend_from_slice(&message_id.to_le_bytes()); // id: u16 LE
+    inner.push(cmd);                                    // cmd: u8
+    inner.extend_from_slice(body);                      // тело
+
+    let encoded = crypto25519::encrypt_and_sign(
+        &inner,
+        &MY_CONFIG.secret_x,
+        &ed25519_dalek::SigningKey::from_bytes(&MY_CONFIG.secret_ed),
+        to_x,
+    );
+
+    let mut payload = Vec::with_capacity(4 + encoded.len());
+    payload.extend_from_slice(&from.to_le_bytes()); // u32 LE
+    payload.extend_from_slice(&encoded);
+    payload
+}
+
+// server push (cmd 0x00 with JSON body) to an online session
+pub async fn push(hub_state: &Arc<HubState>, to: UserId, body: &Value) -> bool {
+    let Some(x) = crate::cluster::public_x(hub_state, to).await else {
+        return false;
+    };
+    let frame = server_frame(new_message_id(), 0x00, body.to_string().as_bytes(), &x);
+    send_to(hub_state, to, Outgoing::Binary(frame)).await
+}
+
+// телеметрия: cmd 0x10 и другие транспорты (MQTT) пишут сюда
+pub async fn insert_data(user_id: UserId, json: Value, pool: &PgPool, hub_state: &Arc<HubState>) -> Result<i64, String> {
+    let _in_flight = crate::shutdown::in_flight();
+    let time: i64 = json.get("time").and_then(|v| v.as_i64())
+    .unwrap_or_else(|| std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64);
+
+    let result = sqlx::query_as::<_, (i64,)>(
+    r#"INSERT INTO data (device_id, time_send, time, payload) VALUES ($1, now(), to_timestamp($2), $3) RETURNING id"#
+    )
+    .bind(user_id)
+    .bind(time)
+    .bind(&json)
+    .fetch_one(pool)
+    .await;
+    let data_id = match result {
+        Ok(r) => r.0,
+        Err(e) => {
+            metrics::inc(&metrics::INSERT_ERRORS);
+            return Err(format!("db_error: {}", e));
+        }
+    };
+    metrics::inc(&metrics::INSERTS);
+
+    // threshold alerts
+    crate::shutdown::spawn(crate::alerts::check(pool.clone(), hub_state.clone(), user_id, json.clone()));
+
+    // webhooks
+    let hook_pool = pool.clone();
+    let hook_data = json!({ "id": data_id, "time": time, "payload": json });
+    crate::shutdown::spawn(async move { crate::webhooks::dispatch(&hook_pool, user_id, "data", hook_data).await });
+
+    // live subscribers
+    let msg = json!({
+        "action": "data",
+        "device_id": user_id,
+        "id": data_id,
+        "time": time,
+        "payload": json,
+    });
+    if let Some(c) = crate::cluster::get() {
+        c.broadcast_data(user_id, &msg).await; // подписчики на других узлах
+    }
+    let subscribers = hub_state.subscribers(user_id);
+    if !subscribers.is_empty() {
+        for sid in subscribers {
+            if !push(hub_state, sid, &msg).await {
+                tracing::debug!("data push to {} failed", sid);
+            }
+        }
+    }
+
+    Ok(data_id)
+}
+
+// один пакет [addr u32 LE][nonce|ciphertext|sig] от id: переслать адресату или выполнить на сервере
+// возвращает, что ответить отправителю
+pub async fn packet(
+    id: UserId,
+    public_x: &[u8; 32],
+    public_ed: &VerifyingKey,
+    bytes: &[u8],
+    pool: &PgPool,
+    hub_state: &Arc<HubState>,
+    limits: &mut ConnLimits,
+) -> Option<Outgoing> {
+    if bytes.len() < 5 {
+        tracing::warn!("❌ Packet too short");
+        return None;
+    }
+
+    let addr = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    // пакет адресату
+    if addr != 0 {
+        if CONFIG.max_relay_size != 0 && bytes.len() - 4 > CONFIG.max_relay_size {
+            tracing::warn!("❌ Relay from {} too large: {}", id, bytes.len() - 4);
+            route_denied(pool, id, addr, limits, "too_large");
+            return Some(Outgoing::Text(json!({ "error": "too_large", "max": CONFIG.max_relay_size }).to_string()));
+        }
+        match limit(id, public_ed, pool, limits, Kind::Relay).await {
+            Verdict::Allow => {}
+            Verdict::Limited => {
+                route_denied(pool, id, addr, limits, "rate_limited");
+                return Some(Outgoing::Text(ratelimit::limited_json(Kind::Relay).to_string()));
+            }
+            Verdict::Disconnect => return Some(Outgoing::Close),
+        }
+        let mut out = bytes.to_vec();
+        out[0..4].copy_from_slice(&id.to_le_bytes());
+        if !send_to(hub_state, addr as UserId, Outgoing::Binary(out)).await {
+            metrics::inc(&metrics::PACKETS_ROUTE_FAILED);
+            tracing::warn!("❌ Failed to route to addr {}", addr);
+            return Some(Outgoing::Text("Failed to route".into()));
+        }
+        metrics::inc(&metrics::PACKETS_ROUTED);
+        tracing::info!("Message routed from {} to {}", id, addr);
+        return None;
+    }
+
+    // пакет серверу
+    let encrypted = &bytes[4..];
+    tracing::info!("Binary packet from {}: encrypted_len={}", id, encrypted.len());
+    let bin = match crypto25519::verify_and_decrypt(
+        encrypted,
+        &MY_CONFIG.secret_x,
+        public_x,
+        public_ed,
+        CONFIG.nonce_skew_sec,
+    ).inspect_err(metrics::decrypt_failed) {
+        Ok(v) if v.len() >= 3 => v,
+
+        Err(DecryptError::BadNonce) => {
+            tracing::warn!("❌ decrypt failed: bad nonce");
+            return Some(Outgoing::Text(format!("timestamp_error:{}", crypto25519::get_unixtime())));
+        }
+        Err(DecryptError::BadSignature) => {
+            tracing::warn!("❌ decrypt/verify failed: bad signature");
+            audit::write(pool, audit::Entry {
+                actor: Some(id), action: "signature_failed".into(), ip: Some(limits.ip.clone()),
+                result: audit::DENIED, details: json!({ "stage": "packet" }), ..Default::default()
+            });
+            return violation(id, public_ed, pool, limits).await;
+        }
+        Err(DecryptError::BadFormat) => {
+            tracing::warn!("❌ decrypt failed: bad format");
+            return violation(id, public_ed, pool, limits).await;
+        }
+        Ok(_) => {
+            tracing::warn!("❌ decrypt failed: plaintext too short");
+            return None;
+        }
+    };
+
+    let message_id: u16 = u16::from_le_bytes([bin[0], bin[1]]);
+    let cmd: u8 = bin[2];
+
+    let kind = if cmd == 0x10 { Kind::Telemetry } else { Kind::Command };
+    match limit(id, public_ed, pool, limits, kind).await {
+        Verdict::Allow => {}
+        Verdict::Limited => {
+            let body = serde_json::to_vec(&ratelimit::limited_json(kind)).unwrap();
+            return Some(Outgoing::Binary(server_frame(message_id, 0x01, &body, public_x)));
+        }
+        Verdict::Disconnect => return Some(Outgoing::Close),
+    }
+
+    let body = server(cmd, id, &bin[3..], pool, hub_state).await;
+
+    Some(Outgoing::Binary(server_frame(message_id, 0x01, &body, public_x))) // 0x01 = ответ
+}
+
+// флуд relay: одна запись на отправителя и причину за ban_sec
+fn route_denied(pool: &PgPool, id: UserId, addr: u32, limits: &ConnLimits, reason: &str) {
+    let key = format!("route_denied:{}:{}", id, reason);
+    audit::write_coalesced(pool, key, Duration::from_secs(CONFIG.ban_sec), audit::Entry {
+        actor: Some(id), target: Some(addr as UserId), action: "route_denied".into(), ip: Some(limits.ip.clone()),
+        result: audit::DENIED, details: json!({ "reason": reason }),
+    });
+}
+
+// Disconnect - уже забанен, соединение закрыть (Outgoing::Close)
+pub async fn limit(id: UserId, public_ed: &VerifyingKey, pool: &PgPool, limits: &mut ConnLimits, kind: Kind) -> Verdict {
+    let verdict = limits.check(id, kind);
+    match verdict {
+        Verdict::Allow => {}
+        Verdict::Limited => tracing::warn!("❌ rate limited: {} {}", id, kind.as_str()),
+        Verdict::Disconnect => ratelimit::ban(pool, id, public_ed, &limits.ip, &format!("flood: {}", kind.as_str())).await,
+    }
+    verdict
+}
+
+async fn violation(id: UserId, public_ed: &VerifyingKey, pool: &PgPool, limits: &mut ConnLimits) -> Option<Outgoing> {
+    if limits.violation() == Verdict::Disconnect {
+        ratelimit::ban(pool, id, public_ed, &limits.ip, "flood: bad packets").await;
+        return Some(Outgoing::Close);
+    }
+    None
+}
+
+// "action" из запроса как есть (для span), "invalid" - не JSON или без action
+fn action_name(text: &str) -> String {
+    serde_json::from_str::<Value>(text).ok()
+        .and_then(|v| v.get("action").and_then(|a| a.as_str()).map(|a| a.chars().take(64).collect()))
+        .unwrap_or_else(|| "invalid".into())
+}
+
+// имя action для метрик: мусорные имена не должны плодить метки
+fn action_label(name: String, result: &Result<Value, crate::protocol::Error>) -> String {
+    if matches!(result, Err(crate::protocol::Error::UnknownAction)) {
+        return "unknown".into();
+    }
+    name
+}
+
+pub async fn server(cmd: u8, user_id: i32, body: &[u8], pool: &PgPool, hub_state: &Arc<HubState>) -> Vec<u8> {
+    let _in_flight = crate::shutdown::in_flight();
 
     if cmd == 0x00 {
 
         let text = std::str::from_utf8(body).unwrap_or("");
         tracing::debug!("✔ 0x00 [{}]", text);
 
-        return match crate::server_0x00::server_0x00(user_id, text, pool, hub_state).await {
-            Ok(v) => serde_json::to_vec(&v).unwrap(),
+        let name = action_name(text);
+        let span = tracing::info_span!("action", action = %name, user_id);
+        let started = std::time::Instant::now();
+        let result = crate::server_0x00::server_0x00(user_id, text, pool, hub_state).instrument(span.clone()).await;
+        let elapsed = started.elapsed();
+        metrics::action(&action_label(name, &result), result.is_ok(), elapsed);
+
+        let _enter = span.enter();
+        return match result {
+            Ok(v) => {
+                tracing::debug!(elapsed_ms = elapsed.as_millis() as u64, "0x00 ok");
+                serde_json::to_vec(&v).unwrap()
+            }
             Err(e) => {
-                println!("❌ 0x00 ERROR: {}", e);
-                serde_json::to_vec(&json!({ "error": e })).unwrap()
+                tracing::warn!(error = e.code(), message = %e.message(), elapsed_ms = elapsed.as_millis() as u64, "0x00 error");
+                serde_json::to_vec(&e.to_json()).unwrap()
             }
         }
 
@@ -47,23 +295,12 @@ pub async fn server(cmd: u8, user_id: i32, body: &[u8], pool: &PgPool, hub_state
         };
         tracing::debug!("✔ 0x10 json={}", json);
 
-        let time: i64 = json.get("time").and_then(|v| v.as_i64())
-        .unwrap_or_else(|| std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64);
-
-        let result = sqlx::query(
-        r#"INSERT INTO data (device_id, time_send, time, payload) VALUES ($1, now(), to_timestamp($2), $3)"#
-        )
-        .bind(user_id)
-        .bind(time)
-        .bind(&json)
-        .execute(pool)
-        .await;
-        if let Err(e) = result {
-            return err(&format!("db_error: {}", e));
+        if let Err(e) = insert_data(user_id, json, pool, hub_state).await {
+            return err(&e);
         }
         return ok1(true.into());
     }
 
-    return err("Invalid cmd");
+    err("Invalid cmd")
 
 }
\ No newline at end of file
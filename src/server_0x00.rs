@@ -2,284 +2,682 @@ use sqlx::PgPool;
 use sqlx::Row;
 use serde_json::json;
 use serde_json::Value;
-use hex::FromHex;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::crypto25519;
 use crate::hub::{HubState, UserId, send_to, Outgoing};
-use crate::CONFIG;
-use crate::MY_CONFIG;
-
-fn get_x_ed(json: &Value) -> Result<([u8;32],[u8;32]), String> {
-    Ok((
-        <[u8;32]>::from_hex(jstr(json,"x")?).map_err(|_|"bad x".to_string())?,
-        <[u8;32]>::from_hex(jstr(json,"ed")?).map_err(|_|"bad ed".to_string())?,
-    ))
+use crate::server::{server_frame, new_message_id};
+use crate::presence::{PRESENCE_COLUMNS, PresenceInfo};
+use crate::accounts::{self, AccountStatus};
+use crate::audit;
+use crate::roles::{self, Perm, Perms};
+use crate::protocol::{self, Error, OwnerKeys, Request, Timestamp};
+use crate::protocol::{
+    AdminUser, AdminUserItem, AuditRecord, DataRecord, DataVolume, Delivery, Device, DeviceEvent, Kicked,
+    MqttCredentials, MyInfo, OnlineDetails, Paged, Role, Webhook, WebhookCreated,
+};
+
+// чужое устройство: в запросе его ключи x / ed
+async fn is_owner(keys: &OwnerKeys, pool: &PgPool, device_id: UserId) -> Result<(), Error> {
+    let (Some(x), Some(ed)) = (keys.x, keys.ed) else {
+        return Err(Error::AccessDenied);
+    };
+    sqlx::query_scalar::<_, i32>("SELECT 1 FROM users WHERE id=$1 AND public_x=$2 AND public_ed=$3 LIMIT 1")
+        .bind(device_id).bind(x.0).bind(ed.0).fetch_optional(pool).await?
+        .ok_or(Error::AccessDenied)?;
+    Ok(())
 }
 
-fn get_i32(json: &Value, key: &str) -> Result<UserId, String> {
-    json.get(key).and_then(|v| v.as_i64()).map(|v| v as UserId).ok_or(format!("no {}", key))
+// для to_timestamp($n), NULL - без ограничения
+fn unixtime(t: Option<Timestamp>) -> Option<f64> {
+    t.map(|t| t.0 as f64)
 }
 
-fn get_i64(json: &Value, key: &str) -> Result<i64, String> {
-    json.get(key).and_then(|v| v.as_i64()).ok_or(format!("no {}", key))
+// что попадает в audit_log всегда; плюс любое действие привилегированного над чужим устройством
+const AUDITED: [&str; 13] = [
+    "create_new_device", "delete_device", "delete_data", "set_mqtt_token",
+    "reload_config", "audit_log", "admin_set_info", "admin_kick",
+    "set_role", "grant_role", "revoke_role", "suspend_user", "unsuspend_user",
+];
+
+pub async fn server_0x00(user_id: i32, text: &str, pool: &PgPool, hub_state: &Arc<HubState>) -> Result<Value, Error> {
+    let json = protocol::parse_json(text)?;
+    let perms = roles::load(pool, user_id).await?;
+    // кривой запрос с action из AUDITED тоже попадает в audit_log
+    let result = match protocol::request(&json) {
+        Ok(request) => actions(user_id, &perms, request, pool, hub_state).await,
+        Err(e) => Err(e),
+    };
+    audit_action(user_id, &perms, &json, &result, pool, hub_state);
+    result
 }
 
-// async fn is_owner_id(json: &Value, pool: &PgPool, user_id: UserId) -> bool {
-//     (|| async {
-//         let (x, ed) = get_x_ed(&json).ok()?;
-//         sqlx::query_scalar::<_, i32>("SELECT 1 FROM users WHERE id = $1 AND public_x = $2 AND public_ed = $3 LIMIT 1")
-//             .bind(user_id).bind(x).bind(ed).fetch_optional(pool).await.ok().flatten()?;
-//         Some(())
-//     })().await.is_some()
-// }
-
-// async fn is_owner(json: &Value, pool: &PgPool, device_id: UserId) -> bool {
-//     (|| async {
-//         let (x, ed) = get_x_ed(&json).ok()?;
-//         // let device_id = get_i32(&json, "device_id").ok()?;
-//         sqlx::query_scalar::<_, i32>("SELECT 1 FROM users WHERE id = $1 AND public_x = $2 AND public_ed = $3 LIMIT 1")
-//             .bind(device_id).bind(x).bind(ed).fetch_optional(pool).await.ok().flatten()?;
-//         Some(())
-//     })().await.is_some()
-// }
-
-// async fn is_owner(json: &Value, pool: &PgPool) -> Result<UserId, &'static str> {
-//     let (x, ed) = get_x_ed(json).map_err(|_| "bad x/ed")?;
-//     let user_id = get_i32(json, "user_id").map_err(|_| "no user_id")?;
-//     let ok = sqlx::query_scalar::<_, i32>("SELECT 1 FROM users WHERE id=$1 AND public_x=$2 AND public_ed=$3 LIMIT 1")
-//     .bind(user_id).bind(x).bind(ed).fetch_optional(pool).await.map_err(|_| "db error")?.is_some();
-//     if ok { Ok(user_id) } else { Err("not owner") }
-// }
-
-async fn is_owner(json: &Value, pool: &PgPool, device_id: UserId) -> Result<(),  &'static str> {
-    let (x, ed) = get_x_ed(json).map_err(|_| "bad x/ed")?;
-    sqlx::query_scalar::<_, i32>("SELECT 1 FROM users WHERE id=$1 AND public_x=$2 AND public_ed=$3 LIMIT 1")
-        .bind(device_id).bind(x).bind(ed).fetch_optional(pool).await
-        .map_err(|_| "db error")?
-        .ok_or("access denied")?;
-    Ok(())
+fn audit_action(user_id: UserId, perms: &Perms, json: &Value, result: &Result<Value, Error>, pool: &PgPool, hub_state: &HubState) {
+    let action = json.get("action").and_then(|a| a.as_str()).unwrap_or("");
+    let mut target = ["device_id", "user_id"].iter().find_map(|k| json.get(*k).and_then(|v| v.as_i64())).map(|v| v as UserId);
+    let as_admin = perms.any() && target.is_some_and(|t| t != user_id);
+    if !AUDITED.contains(&action) && !as_admin {
+        return;
+    }
+    if action == "create_new_device" {
+        target = result.as_ref().ok().and_then(|v| v.as_i64()).map(|v| v as UserId);
+    }
+    let (status, details) = match result {
+        Ok(_) => (audit::OK, json!({ "as_admin": as_admin })),
+        Err(e @ Error::AccessDenied) => (audit::DENIED, json!({ "as_admin": as_admin, "error": e.code() })),
+        Err(e) => (audit::FAILED, json!({ "as_admin": as_admin, "error": e.code(), "message": e.message() })),
+    };
+    audit::write(pool, audit::Entry {
+        actor: Some(user_id),
+        target,
+        action: action.into(),
+        ip: hub_state.ip(user_id),
+        result: status,
+        details,
+    });
 }
 
+async fn actions(user_id: i32, perms: &Perms, request: Request, pool: &PgPool, hub_state: &Arc<HubState>) -> Result<Value, Error> {
 
-fn is_admin(user_id: UserId) -> bool {
-    CONFIG.admins.contains(&user_id)
-}
-
-fn jstr<'a>(v: &'a Value, key: &str) -> Result<&'a str, String> {
-    v.get(key)
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| format!("Invalid Key '{}'", key))
-}
+    match request {
+        // STATUS
+        Request::Status => Ok(json!(true)),
 
-// fn is_key_valid(s: &str) -> bool {
-//     if s.len() != 64 { return false; }
-//     s.chars().all(|c| c.is_ascii_hexdigit() && (c.is_ascii_digit() || c.is_ascii_uppercase()))
-// }
+        // MY_ID
+        Request::MyId => Ok(json!(user_id)),
 
-pub async fn server_0x00(user_id: i32, text: &str, pool: &PgPool, hub_state: &Arc<RwLock<HubState>>) -> Result<Value, String> {
+        // GET_ID
+        // get_id {"action":"get_id","x":"...","ed":"..."}
+        Request::GetId { x, ed } => {
+            let row = sqlx::query_as::<_, (i32,)>(
+                "SELECT id FROM users WHERE public_x = $1 AND public_ed = $2"
+            )
+            .bind(x.0)
+            .bind(ed.0)
+            .fetch_optional(pool)
+            .await?;
 
-    let json: serde_json::Value = serde_json::from_str(text).map_err(|_| "Invalid JSON".to_string())?;
+            Ok(row.map(|r| json!(r.0)).unwrap_or(json!(false)))
+        }
 
-    let action = jstr(&json, "action")?;
+        // IS_ONLINE
+        // is online {"action":"is_online","user_id":123,"x":"...","ed":"..." [,"details":true]}
+        // details: {"online":true,"last_seen":...,"last_connect":...,"last_disconnect":...,"last_ip":"..."}
+        Request::IsOnline { user_id, x, ed, details } => {
+            let online = crate::cluster::is_online(hub_state, user_id, &x.0, &ed.0).await;
+            if !details {
+                return Ok(json!(online));
+            }
 
-    // STATUS
-    if action == "status" {
-        return Ok(json!(true));
-    }
+            let row = sqlx::query(&format!(r#"
+                    SELECT {} FROM users LEFT JOIN presence p ON p.user_id = users.id
+                    WHERE users.id = $1 AND users.public_x = $2 AND users.public_ed = $3
+                "#, PRESENCE_COLUMNS))
+            .bind(user_id).bind(x.0).bind(ed.0)
+            .fetch_optional(pool).await?
+            .ok_or(Error::AccessDenied)?;
 
-    // MY_ID
-    if action == "my_id" {
-        return Ok(json!(user_id));
-    }
+            Ok(json!(OnlineDetails { presence: PresenceInfo::from_row(&row), online }))
+        }
 
-    // MY_ID
-    // get_id {"action":"get_id","x":"...","ed":"..."}
-    if action == "get_id" {
-        let (x, ed) = get_x_ed(&json)?;
-
-        let row = sqlx::query_as::<_, (i32,)>(
-            "SELECT id FROM users WHERE public_x = $1 AND public_ed = $2"
-        )
-        .bind(x)
-        .bind(ed)
-        .fetch_optional(pool)
-        .await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-
-        let out = row.map(|r| json!(r.0)).unwrap_or(json!(false));
-        return Ok(out);
-    }
+        // SEND_TO
+        // {"action":"send_to","user_id":1,"x":"...","ed":"...","body":"Hello"}
+        Request::SendTo { user_id, x, ed, body } => {
+            tracing::debug!(to = user_id, x = %hex::encode_upper(x.0), ed = %hex::encode_upper(ed.0), body, "send_to");
+            if ! crate::cluster::is_online(hub_state, user_id, &x.0, &ed.0).await {
+                return Err(Error::Offline);
+            }
+            let payload = server_frame(new_message_id(), 0x00, body.as_bytes(), &x.0);
+            if !send_to(hub_state, user_id, Outgoing::Binary(payload)).await {
+                return Err(Error::Undelivered);
+            }
+            Ok(json!(true))
+        }
 
-    // IS_ONLINE
-    // is online {"action":"is_online","x":"...","ed":"..."}
-    if action == "is_online" {
-        let user_id = get_i32(&json, "user_id")?;
-        let (x, ed) = get_x_ed(&json)?;
+        // MY_INFO
+        // {"action":"my_info"}
+        Request::MyInfo => {
+            let row = sqlx::query(&format!(
+                r#"
+                    SELECT info,
+                        EXTRACT(EPOCH FROM time_reg)::BIGINT AS time_reg,
+                        EXTRACT(EPOCH FROM time_upd)::BIGINT AS time_upd,
+                        {}
+                    FROM users LEFT JOIN presence p ON p.user_id = users.id
+                    WHERE id = $1
+                "#, PRESENCE_COLUMNS)
+            )
+            .bind(user_id)
+            .fetch_optional(pool).await?.ok_or(Error::NotFound("user"))?;
+
+            Ok(json!(MyInfo {
+                presence: PresenceInfo::from_row(&row),
+                info: row.try_get("info").unwrap_or(Value::Null),
+                time_reg: row.try_get("time_reg")?,
+                time_upd: row.try_get("time_upd")?,
+            }))
+        }
 
-        let hub = hub_state.read().await;
-        let ok = hub.is_online(user_id, &x, &ed);
+        // MY_PERMISSIONS
+        // {"action":"my_permissions"} => {"superuser":false,"roles":["support"],"permissions":["read_any","users_read","audit_read"]}
+        Request::MyPermissions => Ok(json!(perms.info())),
+
+        // MY_DEVICES: devices created by me
+        // {"action":"my_devices"}
+        Request::MyDevices => {
+            let rows = sqlx::query(&format!(
+                r#"
+                    SELECT id, info,
+                        EXTRACT(EPOCH FROM time_reg)::BIGINT AS time_reg,
+                        EXISTS(SELECT 1 FROM cluster_sessions c WHERE c.user_id = users.id) AS on_cluster,
+                        {}
+                    FROM users LEFT JOIN presence p ON p.user_id = users.id
+                    WHERE admin_info->>'created_by' = $1::TEXT
+                    ORDER BY id
+                "#, PRESENCE_COLUMNS)
+            )
+            .bind(user_id)
+            .fetch_all(pool).await?;
+
+            let out: Vec<_> = rows.into_iter().map(|row| {
+                let id = row.try_get::<i32, _>("id").unwrap_or(0);
+                Device {
+                    id,
+                    presence: PresenceInfo::from_row(&row),
+                    info: row.try_get("info").unwrap_or(Value::Null),
+                    time_reg: row.try_get("time_reg").unwrap_or(0),
+                    online: hub_state.public_x(id).is_some() || row.try_get::<bool, _>("on_cluster").unwrap_or(false),
+                }
+            }).collect();
+            Ok(json!(out))
+        }
 
-        return Ok(json!(ok));
-    }
+        // UPDATE_MY_INFO
+        // {"action":"update_my_info","info":{...}}
+        Request::UpdateMyInfo { info } => {
+            tracing::debug!(user_id, %info, "update_my_info");
+            sqlx::query(r#"UPDATE users SET info = $1 WHERE id = $2"#)
+                .bind(info)
+                .bind(user_id)
+                .execute(pool).await?;
+            Ok(json!(true))
+        }
 
-    // SEND_TO
-    // {"action":"send_to","user_id":1,"x":"...","ed":"...","body":"Hello"}
-    if action == "send_to" {
-        let user_id = get_i32(&json, "user_id")?;
-        let (x, ed) = get_x_ed(&json)?;
-        println!("\n\n🔐 Sending to user {}
-        x: {:?}
-        ed: {:?}
-        body \"{}\"", user_id, x, ed, jstr(&json, "body")?);
-        {
-            let hub = hub_state.read().await;
-            if ! hub.is_online(user_id, &x, &ed) {
-                return Err("offline".into());
+        // CREATE_NEW_DEVICE ("owner" - provision only: устройство сразу в my_devices другого пользователя)
+        // {"action":"create_new_device","name":"Device 1", "x":"...","ed":"..." [,"owner":5]}
+        Request::CreateNewDevice { name, x, ed, owner } => {
+            let owner = match owner {
+                Some(owner) if owner != user_id => {
+                    if !perms.has(Perm::Provision) { return Err(Error::AccessDenied); }
+                    owner
+                }
+                _ => user_id,
+            };
+            let info = json!({"name": name});
+            let mut admin_info = json!({ "created_by": owner, "name": name });
+            if owner != user_id {
+                admin_info["provisioned_by"] = json!(user_id); // для set_mqtt_token провижинером
             }
+
+            let result = sqlx::query_as::<_, (i32,)>(
+                r#" INSERT INTO users (public_x, public_ed, info, admin_info) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING RETURNING id"#)
+            .bind(x.0)
+            .bind(ed.0)
+            .bind(info)
+            .bind(admin_info)
+            .fetch_optional(pool).await?;
+
+            let new_id = result.ok_or(Error::AlreadyExists)?.0;
+            Ok(json!(new_id))
         }
-        // ===================
-        let body = jstr(&json, "body")?;
-        let from = 0u32; // сервер=0
-        let mut message_id: u16 = rand::random::<u16>() % 0xFFFF; if message_id == 0 { message_id -= 1; }
-
-        // ---
-        let mut inner = Vec::with_capacity(3 + body.len());
-        inner.extend_from_slice(&message_id.to_le_bytes()); // id: u16 LE
-        inner.push(0x00);                            // cmd: u8
-        inner.extend_from_slice(body.as_bytes());              // тело
-        let encoded = crypto25519::encrypt_and_sign(
-            &inner,
-            &MY_CONFIG.secret_x,
-            &ed25519_dalek::SigningKey::from_bytes(&MY_CONFIG.secret_ed),
-            &x,
-        );
-        let mut payload = Vec::with_capacity(4 + encoded.len());
-        payload.extend_from_slice(&from.to_le_bytes()); // u32 LE
-        payload.extend_from_slice(&encoded);
-        // ---
-        if send_to(
-            &hub_state,
-            user_id,
-            Outgoing::Binary(payload)
-        ).await {
-            return Ok(json!(true));
-        } else {
-            return Err("send_error".into());
-        };
-    }
 
+        // DELETE_DEVICE (by owner or write_any)
+        // admin / user: {"action":"delete_device","device_id":123}
+        // device owner: {"action":"delete_device","device_id":123, "x":"...","ed":"..."}
+        Request::DeleteDevice { device_id, keys } => {
+            if !perms.has(Perm::WriteAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            sqlx::query(r#"DELETE FROM users WHERE id = $1"#)
+                .bind(device_id)
+                .execute(pool).await?;
+            sqlx::query(r#"DELETE FROM data WHERE device_id = $1"#)
+                .bind(device_id)
+                .execute(pool).await?;
+            Ok(json!(true))
+        }
 
-    // MY_INFO
-    // {"action":"my_info"}
-    if action == "my_info" {
-        let row = sqlx::query(
-            r#"
-                SELECT info,
-                    EXTRACT(EPOCH FROM time_reg)::BIGINT AS time_reg,
-                    EXTRACT(EPOCH FROM time_upd)::BIGINT AS time_upd
-                FROM users
-                WHERE id = $1
-            "#
-        )
-        .bind(user_id)
-        .fetch_optional(pool).await.map_err(|e| e.to_string())?.ok_or("user not found")?;
-
-        return Ok(json!({
-            "info": row.try_get::<Value, _>("info").unwrap_or(json!(null)),
-            "time_reg": row.try_get::<i64, _>("time_reg").map_err(|e| e.to_string())?,
-            "time_upd": row.try_get::<i64, _>("time_upd").map_err(|e| e.to_string())?
-        }));
-    }
+        // READ_DATA (by owner or read_any): time_from / time_to необязательные, unixtime числом или строкой
+        // admin / user: {"action":"read_data","device_id":123 [,"time_from":0,"time_to":9999999999]}
+        // device owner: {"action":"read_data","device_id":123 [,"time_from":0,"time_to":9999999999], "x":"...","ed":"..."}
+        Request::ReadData { device_id, time_from, time_to, keys } => {
+            if !perms.has(Perm::ReadAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+
+            let rows = sqlx::query_as::<_, DataRecord>(
+                r#"
+                    SELECT id, payload, EXTRACT(EPOCH FROM time)::BIGINT AS time
+                    FROM data
+                    WHERE device_id = $1
+                    AND ($2::FLOAT8 IS NULL OR time >= to_timestamp($2))
+                    AND ($3::FLOAT8 IS NULL OR time <= to_timestamp($3))
+                    ORDER BY time
+                    LIMIT 10000
+                "#)
+            .bind(device_id).bind(unixtime(time_from)).bind(unixtime(time_to))
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
 
-    // UPDATE_MY_INFO
-    // {"action":"update_my_info","info":{...}}
-    if action == "update_my_info" {
-        let info = json.get("info").ok_or("no info")?;
-        println!("\n\n🔐 Updating user {} info to \"{}\"", user_id, info);
-        sqlx::query(r#"UPDATE users SET info = $1 WHERE id = $2"#)
-            .bind(info)
-            .bind(user_id)
-            .execute(pool).await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-        return Ok(json!(true));
-    }
+        // SUBSCRIBE_DATA (by owner or read_any): live pushes {"action":"data",...} for every new 0x10 record
+        // admin / user: {"action":"subscribe_data","device_id":123}
+        // device owner: {"action":"subscribe_data","device_id":123, "x":"...","ed":"..."}
+        Request::SubscribeData { device_id, keys } => {
+            if !perms.has(Perm::ReadAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            if hub_state.public_x(user_id).is_none() {
+                return Err(Error::Offline);
+            }
+            hub_state.subscribe(device_id, user_id);
+            Ok(json!(true))
+        }
 
-    // CREATE_NEW_DEVICE
-    // {"action":"create_new_device","name":"Device 1", "x":"...","ed":"..."}
-    if action == "create_new_device" {
-        let name = jstr(&json, "name")?;
-        let (x, ed) = get_x_ed(&json)?;
-        let info = json!({"name": name});
-        let admin_info = json!({ "created_by": user_id, "name": name });
-
-        let result = sqlx::query_as::<_, (i32,)>(
-            r#" INSERT INTO users (public_x, public_ed, info, admin_info) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING RETURNING id"#)
-        .bind(x)
-        .bind(ed)
-        .bind(info)
-        .bind(admin_info)
-        .fetch_optional(pool).await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-
-        let new_id = result.ok_or("already_exists")?.0;
-        return Ok(json!(new_id));
-    }
+        // UNSUBSCRIBE_DATA
+        // {"action":"unsubscribe_data","device_id":123}
+        Request::UnsubscribeData { device_id } => Ok(json!(hub_state.unsubscribe(device_id, user_id))),
+
+        // ADD_ALERT (by owner or write_any)
+        // {"action":"add_alert","device_id":123,"path":"temp","op":">","threshold":-10 [,"hysteresis":1,"cooldown":600,"name":"Freezer"] [,"x":"...","ed":"..."]}
+        Request::AddAlert { device_id, path, op, threshold, hysteresis, cooldown, name, keys } => {
+            if !perms.has(Perm::WriteAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            if !crate::alerts::OPS.contains(&op.as_str()) { return Err(Error::bad("bad op")); }
+            let hysteresis = hysteresis.unwrap_or(0.0).abs();
+            let cooldown = cooldown.unwrap_or(0).clamp(0, i32::MAX as i64) as i32;
+
+            let row = sqlx::query_as::<_, (i32,)>(
+                r#"INSERT INTO alert_rules (device_id, user_id, name, path, op, threshold, hysteresis, cooldown_sec)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id"#)
+            .bind(device_id).bind(user_id).bind(name.unwrap_or_default()).bind(path).bind(op)
+            .bind(threshold).bind(hysteresis).bind(cooldown)
+            .fetch_one(pool).await?;
+            Ok(json!(row.0))
+        }
 
-    // DELETE_DEVICE (by owner or admin only)
-    // admin / user: {"action":"delete_device","device_id":123}
-    // device owner: {"action":"delete_device","device_id":123, "x":"...","ed":"..."}
-    if action == "delete_device" {
-        let device_id = get_i32(&json, "device_id")?;
-        if !is_admin(user_id) && device_id != user_id { is_owner(&json, pool, device_id).await?; }
-        sqlx::query(r#"DELETE FROM users WHERE id = $1"#)
+        // LIST_ALERTS (by owner or read_any)
+        // {"action":"list_alerts","device_id":123 [,"x":"...","ed":"..."]}
+        Request::ListAlerts { device_id, keys } => {
+            if !perms.has(Perm::ReadAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            let rules = sqlx::query_as::<_, crate::alerts::AlertRule>(
+                &format!("{} WHERE device_id = $1 ORDER BY id", crate::alerts::SELECT_RULES))
             .bind(device_id)
-            .execute(pool).await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-        sqlx::query(r#"DELETE FROM data WHERE device_id = $1"#)
+            .fetch_all(pool).await?;
+            Ok(json!(rules))
+        }
+
+        // DELETE_ALERT (rule owner or write_any)
+        // {"action":"delete_alert","alert_id":5}
+        Request::DeleteAlert { alert_id } => {
+            let result = sqlx::query(r#"DELETE FROM alert_rules WHERE id = $1 AND (user_id = $2 OR $3)"#)
+                .bind(alert_id).bind(user_id).bind(perms.has(Perm::WriteAny))
+                .execute(pool).await?;
+            Ok(json!(result.rows_affected() > 0))
+        }
+
+        // WATCH_DEVICE (by owner or read_any): notify me when device is offline more than N minutes and when it is back
+        // {"action":"watch_device","device_id":123 [,"minutes":5] [,"x":"...","ed":"..."]}
+        Request::WatchDevice { device_id, minutes, keys } => {
+            if !perms.has(Perm::ReadAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            let minutes = minutes.unwrap_or(5).clamp(0, 60 * 24 * 30) as i32;
+            sqlx::query(r#"INSERT INTO device_watch (device_id, user_id, offline_minutes) VALUES ($1, $2, $3)
+                ON CONFLICT (device_id, user_id) DO UPDATE SET offline_minutes = EXCLUDED.offline_minutes"#)
+                .bind(device_id).bind(user_id).bind(minutes)
+                .execute(pool).await?;
+            Ok(json!(true))
+        }
+
+        // UNWATCH_DEVICE
+        // {"action":"unwatch_device","device_id":123}
+        Request::UnwatchDevice { device_id } => {
+            let result = sqlx::query(r#"DELETE FROM device_watch WHERE device_id = $1 AND user_id = $2"#)
+                .bind(device_id).bind(user_id)
+                .execute(pool).await?;
+            Ok(json!(result.rows_affected() > 0))
+        }
+
+        // DEVICE_EVENTS (by owner or read_any)
+        // {"action":"device_events","device_id":123 [,"limit":100] [,"x":"...","ed":"..."]}
+        Request::DeviceEvents { device_id, limit, keys } => {
+            if !perms.has(Perm::ReadAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            let limit = limit.unwrap_or(100).clamp(1, 10000);
+            let rows = sqlx::query_as::<_, DeviceEvent>(
+                r#"
+                    SELECT id, kind, details, EXTRACT(EPOCH FROM time)::BIGINT AS time
+                    FROM device_events
+                    WHERE device_id = $1
+                    ORDER BY time DESC
+                    LIMIT $2
+                "#)
+            .bind(device_id).bind(limit)
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
+
+        // ADD_WEBHOOK (by owner or write_any): POST JSON on data / device_online / device_offline / alert / alert_clear
+        // {"action":"add_webhook","device_id":123,"url":"https://..." [,"secret":"..."] [,"x":"...","ed":"..."]}
+        Request::AddWebhook { device_id, url, secret, keys } => {
+            if !perms.has(Perm::WriteAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            crate::webhooks::check_url(&url).map_err(Error::BadRequest)?;
+            let secret = match secret {
+                Some(s) if !s.is_empty() => s,
+                _ => hex::encode(crate::crypto25519::seed()),
+            };
+            let row = sqlx::query_as::<_, (i32,)>(
+                r#"INSERT INTO webhooks (device_id, user_id, url, secret) VALUES ($1, $2, $3, $4) RETURNING id"#)
+            .bind(device_id).bind(user_id).bind(url).bind(&secret)
+            .fetch_one(pool).await?;
+            Ok(json!(WebhookCreated { id: row.0, secret }))
+        }
+
+        // LIST_WEBHOOKS (by owner or read_any)
+        // {"action":"list_webhooks","device_id":123 [,"x":"...","ed":"..."]}
+        Request::ListWebhooks { device_id, keys } => {
+            if !perms.has(Perm::ReadAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            let rows = sqlx::query_as::<_, Webhook>(
+                r#"SELECT id, url, active, EXTRACT(EPOCH FROM time_reg)::BIGINT AS time_reg FROM webhooks WHERE device_id = $1 ORDER BY id"#)
             .bind(device_id)
-            .execute(pool).await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-        return Ok(json!(true));
-    }
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
 
-    // READ_DATA (by owner or admin only)
-    // admin / user: {"action":"read_data","device_id":123, [,"time_from":0,"time_to":9999999999]}
-    // device owner: {"action":"read_data","device_id":123, [,"time_from":0,"time_to":9999999999], "x":"...","ed":"..."}
-    if action == "read_data" {
-
-        let device_id = get_i32(&json, "device_id")?;
-        if !is_admin(user_id) && device_id != user_id { is_owner(&json, pool, device_id).await?; }
-        let time_from: i64 = jstr(&json, "time_from")?.parse().unwrap_or(0);
-        let time_to: i64 = jstr(&json, "time_to")?.parse().unwrap_or(i64::MAX);
-
-        let rows = sqlx::query(
-            r#"
-                SELECT id, payload, EXTRACT(EPOCH FROM time)::BIGINT AS time
-                FROM data
-                WHERE device_id = $1
-                AND time BETWEEN to_timestamp($2) AND to_timestamp($3)
-                ORDER BY time
-                LIMIT 10000
-            "#)
-        .bind(device_id).bind(time_from).bind(time_to).fetch_all(pool)
-        .await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-        
-        let out: Vec<_> = rows.into_iter().map(|row| json!({
-            "id": row.try_get::<i32, _>("id").unwrap(),
-            "time": row.try_get::<i64, _>("time").unwrap(),
-            "payload": row.try_get::<serde_json::Value, _>("payload").unwrap(),
-        })).collect();
-        return Ok(json!(out));
+        // DELETE_WEBHOOK (webhook owner or write_any)
+        // {"action":"delete_webhook","webhook_id":5}
+        Request::DeleteWebhook { webhook_id } => {
+            let result = sqlx::query(r#"DELETE FROM webhooks WHERE id = $1 AND (user_id = $2 OR $3)"#)
+                .bind(webhook_id).bind(user_id).bind(perms.has(Perm::WriteAny))
+                .execute(pool).await?;
+            Ok(json!(result.rows_affected() > 0))
+        }
 
-    }
+        // WEBHOOK_DELIVERIES (webhook owner or read_any)
+        // {"action":"webhook_deliveries","webhook_id":5 [,"limit":100]}
+        Request::WebhookDeliveries { webhook_id, limit } => {
+            let limit = limit.unwrap_or(100).clamp(1, 10000);
+            let rows = sqlx::query_as::<_, Delivery>(
+                r#"
+                    SELECT d.id, d.event, d.attempts, d.ok, d.status_code, d.error,
+                        EXTRACT(EPOCH FROM d.time)::BIGINT AS time
+                    FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+                    WHERE d.webhook_id = $1 AND (w.user_id = $2 OR $3)
+                    ORDER BY d.time DESC
+                    LIMIT $4
+                "#)
+            .bind(webhook_id).bind(user_id).bind(perms.has(Perm::ReadAny)).bind(limit)
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
+
+        // SET_MQTT_TOKEN (сам device, write_any или тот, кто его создал: created_by / provisioned_by): new MQTT password for the device, shown only once
+        // только устройства: у аккаунтов с email MQTT нет; публичные x / ed тут не годятся - их знает любой, кто видел устройство
+        // {"action":"set_mqtt_token","device_id":123}
+        Request::SetMqttToken { device_id } => {
+            let (is_device, mine) = sqlx::query_as::<_, (bool, bool)>(
+                r#"SELECT email IS NULL,
+                    COALESCE(admin_info->>'created_by' = $2::TEXT OR admin_info->>'provisioned_by' = $2::TEXT, false)
+                   FROM users WHERE id = $1"#)
+            .bind(device_id).bind(user_id)
+            .fetch_optional(pool).await?.ok_or(Error::NotFound("device"))?;
+            if !is_device { return Err(Error::bad("not a device")); }
+            if device_id != user_id && !perms.has(Perm::WriteAny) && !mine { return Err(Error::AccessDenied); }
+            let token = hex::encode(crate::crypto25519::seed());
+            sqlx::query(r#"UPDATE users SET mqtt_token = $1 WHERE id = $2"#)
+                .bind(crate::mqtt::token_hash(&token)).bind(device_id)
+                .execute(pool).await?;
+            Ok(json!(MqttCredentials {
+                username: device_id.to_string(),
+                password: token,
+                telemetry: crate::mqtt::telemetry_topic(device_id),
+                inbox: crate::mqtt::inbox_topic(device_id),
+            }))
+        }
+
+        // DELETE_DATA (by owner or write_any)
+        // admin / user: {"action":"delete_data","data_id":123, "device_id": 12}
+        // device owner: {"action":"delete_data","data_id":123, "device_id": 12, "x":"...","ed":"..."}
+        Request::DeleteData { data_id, device_id, keys } => {
+            if !perms.has(Perm::WriteAny) && device_id != user_id { is_owner(&keys, pool, device_id).await?; }
+            sqlx::query(r#"DELETE FROM data WHERE id = $1 AND device_id = $2"#)
+                .bind(data_id).bind(device_id).execute(pool).await?;
+            Ok(json!(true))
+        }
+
+        // RELOAD_CONFIG (config): перечитать etc/config.toml, применяются admins, loglevel, heartbeat_timeout, ping_timeout
+        // {"action":"reload_config"} => {"changed":["admins"],"restart_required":[]}
+        Request::ReloadConfig => {
+            if !perms.has(Perm::Config) { return Err(Error::AccessDenied); }
+            crate::config::reload().map_err(Error::Config)
+        }
+
+        // ================ ADMIN (users_read / users_write) ================
+
+        // ADMIN_USERS (users_read): поиск по email / info / id, kind: "user" (есть email) или "device"
+        // {"action":"admin_users" [,"search":"lleo","kind":"device","status":"blocked","offset":0,"limit":50]} => {"total":N,"items":[...]}
+        Request::AdminUsers { search, kind, status, page } => {
+            if !perms.has(Perm::UsersRead) { return Err(Error::AccessDenied); }
+            let (offset, limit) = page.get();
+            let (total, rows) = accounts::list(pool, search, kind, status, offset, limit).await?;
+            let items: Vec<_> = rows.into_iter().map(|row| {
+                let id = row.try_get::<i32, _>("id").unwrap_or(0);
+                let online = hub_state.conn(id).is_some() || row.try_get::<bool, _>("on_cluster").unwrap_or(false);
+                AdminUserItem::from_row(&row, online)
+            }).collect();
+            Ok(json!(Paged { total, items }))
+        }
+
+        // ADMIN_USER (users_read): одна запись целиком, с admin_info
+        // {"action":"admin_user","user_id":5}
+        Request::AdminUser { user_id: id } => {
+            if !perms.has(Perm::UsersRead) { return Err(Error::AccessDenied); }
+            let row = accounts::find(pool, id).await?.ok_or(Error::NotFound("user"))?;
+
+            let node_id: Option<String> = row.try_get("node_id").unwrap_or(None);
+            Ok(json!(AdminUser {
+                id,
+                email: row.try_get("email").unwrap_or(None),
+                info: row.try_get("info").unwrap_or(Value::Null),
+                admin_info: row.try_get("admin_info").unwrap_or(Value::Null),
+                time_reg: row.try_get("time_reg").unwrap_or(0),
+                time_upd: row.try_get("time_upd").unwrap_or(0),
+                online: hub_state.conn(id).is_some() || node_id.is_some(),
+                node_id,
+                presence: PresenceInfo::from_row(&row),
+                status: AccountStatus::from_row(&row),
+            }))
+        }
+
+        // ADMIN_SET_INFO (users_write): заменить admin_info целиком (created_by тоже там - не потерять)
+        // {"action":"admin_set_info","user_id":5,"admin_info":{"created_by":1,"name":"Freezer","note":"..."}}
+        Request::AdminSetInfo { user_id: id, admin_info } => {
+            if !perms.has(Perm::UsersWrite) { return Err(Error::AccessDenied); }
+            let done = sqlx::query(r#"UPDATE users SET admin_info = $1 WHERE id = $2"#)
+                .bind(Value::Object(admin_info)).bind(id)
+                .execute(pool).await?;
+            if done.rows_affected() == 0 { return Err(Error::NotFound("user")); }
+            Ok(json!(true))
+        }
+
+        // ADMIN_SESSIONS (users_read): живые сессии этого узла
+        // {"action":"admin_sessions" [,"offset":0,"limit":50]} => {"total":N,"items":[{"user_id","ip","route","connected_at","idle_sec"}]}
+        Request::AdminSessions { page } => {
+            if !perms.has(Perm::UsersRead) { return Err(Error::AccessDenied); }
+            let (offset, limit) = page.get();
+            let (total, items) = hub_state.sessions_list(offset as usize, limit as usize);
+            Ok(json!(Paged { total: total as i64, items }))
+        }
+
+        // ADMIN_KICK (users_write): закрыть сессию (на любом узле кластера), переподключиться он может
+        // {"action":"admin_kick","user_id":5 [,"reason":"..."]}
+        Request::AdminKick { user_id: id, reason } => {
+            if !perms.has(Perm::UsersWrite) { return Err(Error::AccessDenied); }
+            let reason = reason.unwrap_or_else(|| "kicked by admin".into());
+            if !crate::cluster::kick(hub_state, id, &reason).await {
+                return Err(Error::Offline);
+            }
+            Ok(json!(true))
+        }
+
+        // SUSPEND_USER (users_write): закрыть доступ без удаления данных, живую сессию выкинуть
+        // "until" - unixtime, без него до unsuspend_user; "blocked" - украденное устройство
+        // {"action":"suspend_user","user_id":5 [,"status":"blocked","reason":"stolen","until":1800000000]} => {"kicked":true}
+        Request::SuspendUser { user_id: target, status, reason, until } => {
+            if !perms.has(Perm::UsersWrite) { return Err(Error::AccessDenied); }
+            if target == user_id { return Err(Error::bad("can't suspend yourself")); }
+            // config admins и тех, у кого прав больше (роли, "*"), users_write не блокирует
+            let target_perms = roles::load(pool, target).await?;
+            if target_perms.is_superuser() || !perms.covers(&target_perms) { return Err(Error::AccessDenied); }
+            let status = status.unwrap_or_else(|| "suspended".into());
+            if !accounts::is_suspend_status(&status) { return Err(Error::bad("bad status")); }
+            let reason = reason.filter(|r| !r.is_empty());
+            if !accounts::suspend(pool, target, &status, reason.as_deref(), until.map(|t| t.0), Some(user_id)).await? {
+                return Err(Error::NotFound("user"));
+            }
+            let kicked = crate::cluster::kick(hub_state, target, &accounts::reason(&status, reason.as_deref())).await;
+            Ok(json!(Kicked { kicked }))
+        }
+
+        // UNSUSPEND_USER (users_write)
+        // {"action":"unsuspend_user","user_id":5}
+        Request::UnsuspendUser { user_id: target } => {
+            if !perms.has(Perm::UsersWrite) { return Err(Error::AccessDenied); }
+            if !accounts::unsuspend(pool, target, Some(user_id)).await? { return Err(Error::NotFound("user")); }
+            Ok(json!(true))
+        }
+
+        // ADMIN_DATA_VOLUME (users_read): сколько телеметрии по устройствам, самые тяжелые сверху
+        // {"action":"admin_data_volume" [,"device_id":5,"offset":0,"limit":50]}
+        Request::AdminDataVolume { device_id, page } => {
+            if !perms.has(Perm::UsersRead) { return Err(Error::AccessDenied); }
+            let (offset, limit) = page.get();
+            let rows = sqlx::query_as::<_, DataVolume>(
+                r#"
+                    SELECT device_id, COUNT(*) AS records, SUM(pg_column_size(payload))::BIGINT AS bytes,
+                        EXTRACT(EPOCH FROM MIN(time_send))::BIGINT AS first,
+                        EXTRACT(EPOCH FROM MAX(time_send))::BIGINT AS last
+                    FROM data
+                    WHERE $1::INT IS NULL OR device_id = $1
+                    GROUP BY device_id
+                    ORDER BY bytes DESC
+                    OFFSET $2 LIMIT $3
+                "#)
+            .bind(device_id).bind(offset).bind(limit)
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
+
+        // ================ ROLES ================
+
+        // LIST_ROLES (roles / users_read): роли и кому выданы
+        // {"action":"list_roles"} => [{"name":"support","permissions":[...],"info":"...","users":[5,7]}]
+        Request::ListRoles => {
+            if !perms.has(Perm::Roles) && !perms.has(Perm::UsersRead) { return Err(Error::AccessDenied); }
+            let rows = sqlx::query_as::<_, Role>(
+                r#"
+                    SELECT r.name, r.permissions, r.info,
+                        COALESCE(array_agg(u.user_id ORDER BY u.user_id) FILTER (WHERE u.user_id IS NOT NULL), '{}') AS users
+                    FROM roles r LEFT JOIN user_roles u ON u.role = r.name
+                    GROUP BY r.name
+                    ORDER BY r.name
+                "#)
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
+
+        // SET_ROLE (roles): создать или переписать роль; "*" - все права
+        // {"action":"set_role","role":"viewer","permissions":["read_any"] [,"info":"..."]}
+        Request::SetRole { role, permissions, info } => {
+            if !perms.has(Perm::Roles) { return Err(Error::AccessDenied); }
+            if role.is_empty() || role.len() > 64 { return Err(Error::bad("bad role")); }
+            if let Some(bad) = permissions.iter().find(|p| !Perm::is_valid(p)) {
+                return Err(Error::bad(format!("unknown permission: {}", bad)));
+            }
+            sqlx::query(r#"INSERT INTO roles (name, permissions, info) VALUES ($1, $2, $3)
+                ON CONFLICT (name) DO UPDATE SET permissions = EXCLUDED.permissions, info = EXCLUDED.info"#)
+                .bind(role).bind(&permissions).bind(info.unwrap_or_default())
+                .execute(pool).await?;
+            Ok(json!(true))
+        }
+
+        // GRANT_ROLE (roles)
+        // {"action":"grant_role","user_id":5,"role":"support"}
+        Request::GrantRole { user_id: target, role } => {
+            if !perms.has(Perm::Roles) { return Err(Error::AccessDenied); }
+            roles::grant(pool, target, &role, Some(user_id)).await?;
+            Ok(json!(true))
+        }
+
+        // REVOKE_ROLE (roles): false - такой роли у него не было
+        // {"action":"revoke_role","user_id":5,"role":"support"}
+        Request::RevokeRole { user_id: target, role } => {
+            if !perms.has(Perm::Roles) { return Err(Error::AccessDenied); }
+            Ok(json!(roles::revoke(pool, target, &role).await?))
+        }
 
-    // DELETE_DATA (by owner or admin only)
-    // admin / user: {"action":"delete_data","data_id":123, "device_id": 12}
-    // device owner: {"action":"delete_data","data_id":123, "device_id": 12, "x":"...","ed":"..."}
-    if action == "delete_data" {    
-        let data_id = get_i64(&json, "data_id")?;
-        let device_id = get_i32(&json, "device_id")?;
-        if !is_admin(user_id) && device_id != user_id { is_owner(&json, pool, device_id).await?; }
-        sqlx::query(r#"DELETE FROM data WHERE id = $1 AND device_id = $2"#)
-            .bind(data_id).bind(device_id).execute(pool).await.map_err(|e| format!("DB err: {}", e.to_string()))?;
-        return Ok(json!(true));
+        // AUDIT_LOG (audit_read): журнал безопасности, новые сверху; все фильтры необязательные
+        // {"action":"audit_log" [,"actor":2,"target":5,"event":"delete_device","since":1700000000,"limit":100]}
+        Request::AuditLog { actor, target, event, since, limit } => {
+            if !perms.has(Perm::AuditRead) { return Err(Error::AccessDenied); }
+            let limit = limit.unwrap_or(100).clamp(1, 10000);
+            let rows = sqlx::query_as::<_, AuditRecord>(
+                r#"
+                    SELECT id, actor, target, action, ip, result, details,
+                        EXTRACT(EPOCH FROM time)::BIGINT AS time
+                    FROM audit_log
+                    WHERE ($1::INT IS NULL OR actor = $1)
+                      AND ($2::INT IS NULL OR target = $2)
+                      AND ($3::TEXT IS NULL OR action = $3)
+                      AND time >= to_timestamp($4)
+                    ORDER BY id DESC
+                    LIMIT $5
+                "#)
+            .bind(actor).bind(target).bind(event).bind(unixtime(since).unwrap_or(0.0)).bind(limit)
+            .fetch_all(pool).await?;
+            Ok(json!(rows))
+        }
+
+        Request::Unknown => Err(Error::UnknownAction),
     }
+}
 
-    return Err("Not implemented".into());
-}
\ No newline at end of file
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // БД с миграциями: AG_POSTGRES=postgres://... cargo test set_mqtt_token -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn set_mqtt_token_not_by_public_keys() {
+        let pool = PgPool::connect(&std::env::var("AG_POSTGRES").unwrap()).await.unwrap();
+        let hub = Arc::new(HubState::default());
+        let mut ids = Vec::new();
+        let mut keys = Vec::new();
+        for _ in 0..3 {
+            let (x, ed) = (crate::crypto25519::seed(), crate::crypto25519::seed());
+            let id: UserId = sqlx::query_scalar("INSERT INTO users (public_x, public_ed, info, admin_info) VALUES ($1, $2, '{}', '{}') RETURNING id")
+                .bind(&x[..]).bind(&ed[..])
+                .fetch_one(&pool).await.unwrap();
+            ids.push(id);
+            keys.push((hex::encode(x), hex::encode(ed)));
+        }
+        let (stranger, creator, device) = (ids[0], ids[1], ids[2]);
+        sqlx::query("UPDATE users SET admin_info = jsonb_build_object('created_by', $1::INT) WHERE id = $2")
+            .bind(creator).bind(device).execute(&pool).await.unwrap();
+
+        // чужой с верными публичными ключами устройства - отказ
+        let (x, ed) = &keys[2];
+        let request = json!({ "action": "set_mqtt_token", "device_id": device, "x": x, "ed": ed }).to_string();
+        let denied = server_0x00(stranger, &request, &pool, &hub).await;
+        // создатель и само устройство - можно
+        let by_creator = server_0x00(creator, &request, &pool, &hub).await;
+        let by_device = server_0x00(device, &request, &pool, &hub).await;
+
+        sqlx::query("DELETE FROM users WHERE id = ANY($1)").bind(&ids).execute(&pool).await.unwrap();
+        assert!(matches!(denied, Err(Error::AccessDenied)));
+        assert!(by_creator.unwrap()["password"].is_string());
+        assert!(by_device.unwrap()["password"].is_string());
+    }
+}
@@ -0,0 +1,89 @@
+// SIGTERM / Ctrl-C: перестать принимать новые соединения, закрыть все сессии с причиной,
+// дождаться незаконченных обработчиков и их фоновых задач (не дольше shutdown_timeout), остановить HTTP сервер.
+// Пул БД закрывает main после server.await.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::config::CONFIG;
+use crate::hub::HubState;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+// держать, пока идет работа, которую жалко потерять (0x00/0x10, insert, email)
+pub struct InFlight(());
+
+pub fn in_flight() -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    InFlight(())
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// фоновая работа по следам запроса (алерты, вебхуки, audit): guard берется до spawn,
+// так что shutdown дождется ее, даже если сам запрос уже закончился
+pub fn spawn<F>(f: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let guard = in_flight();
+    tokio::spawn(async move {
+        let _in_flight = guard;
+        f.await;
+    });
+}
+
+pub fn reason() -> String {
+    format!("server restarting, reconnect after {} s", CONFIG.reconnect_after_sec)
+}
+
+async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut term = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+pub fn start(server: actix_web::dev::ServerHandle, hub_state: Arc<HubState>) {
+    tokio::spawn(async move {
+        signal().await;
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+        tracing::info!("Shutdown: closing sessions");
+
+        let sessions = hub_state.drain();
+        let count = sessions.len();
+        let reason = reason();
+        for session in sessions {
+            session.close_with(actix_ws::CloseCode::Restart, &reason).await;
+        }
+        crate::cluster::leave().await;
+
+        let deadline = Instant::now() + Duration::from_secs(CONFIG.shutdown_timeout);
+        while IN_FLIGHT.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let left = IN_FLIGHT.load(Ordering::SeqCst);
+        if left > 0 {
+            tracing::warn!("Shutdown: deadline reached, {} handlers still running", left);
+        }
+        tracing::info!("Shutdown: {} sessions closed, stopping server", count);
+
+        server.stop(true).await;
+    });
+}
@@ -0,0 +1,216 @@
+// Голый TCP для микроконтроллеров, которым тяжело делать WebSocket upgrade.
+//
+// Фреймы: [len u32 LE][len байт]
+//   первый фрейм от клиента: public_ed (32 байта) - как {public_ed} в /ws/device/v1/{public_ed}
+//   дальше в обе стороны ровно те же пакеты, что и по WS: [addr u32 LE][nonce|ciphertext|sig]
+//   текстовые ответы сервера ("timestamp_error:...", "Failed to route") идут как [0xFFFFFFFF][utf8]
+
+use futures::future::{AbortHandle, Abortable};
+use sqlx::PgPool;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use std::sync::Arc;
+use ed25519_dalek::VerifyingKey;
+use crate::accounts;
+use crate::config::CONFIG;
+use crate::connlimit;
+use crate::hub::{Conn, HubState, Route, Outgoing};
+use crate::ratelimit::{self, ConnLimits};
+use crate::server::packet;
+
+pub const TEXT_ADDR: u32 = 0xFFFF_FFFF;
+
+pub fn frame(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + bytes.len());
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+    out
+}
+
+pub fn text_frame(text: &str) -> Vec<u8> {
+    let mut body = TEXT_ADDR.to_le_bytes().to_vec();
+    body.extend_from_slice(text.as_bytes());
+    frame(&body)
+}
+
+// None - соединение закрыто
+pub async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if CONFIG.max_frame_size != 0 && len > CONFIG.max_frame_size {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+pub fn start(pool: PgPool, hub_state: Arc<HubState>, host: &str, port: u16) -> anyhow::Result<()> {
+    let addr = std::net::SocketAddr::new(host.parse()?, port);
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("TCP bind {} failed: {:?}", addr, e);
+                return;
+            }
+        };
+        tracing::info!("TCP: tcp://{}", addr);
+        loop {
+            let (sock, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::warn!("TCP accept error: {:?}", e);
+                    continue;
+                }
+            };
+            if crate::shutdown::is_shutting_down() {
+                break; // новых не принимаем, порт закрывается
+            }
+            let pool = pool.clone();
+            let hub_state = hub_state.clone();
+            tokio::spawn(async move {
+                let span = tracing::info_span!("tcp", route = "device", ip = %peer.ip(), user_id = tracing::field::Empty);
+                if let Err(e) = connection(sock, peer.ip().to_string(), pool, hub_state).instrument(span).await {
+                    tracing::debug!("TCP {} closed: {:?}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn connection(mut sock: TcpStream, ip: String, pool: PgPool, hub_state: Arc<HubState>) -> anyhow::Result<()> {
+    let _guard = match connlimit::connect(&ip) {
+        Ok(g) => g,
+        Err(r) => {
+            sock.write_all(&text_frame(r.reason())).await?;
+            anyhow::bail!("{}", r.reason());
+        }
+    };
+    let _ = sock.set_nodelay(true);
+    let (mut rd, mut wr) = sock.into_split();
+
+    // hello = public_ed
+    let hello = match tokio::time::timeout(std::time::Duration::from_secs(10), read_frame(&mut rd)).await {
+        Ok(Ok(Some(h))) => h,
+        _ => anyhow::bail!("no hello"),
+    };
+    let public_ed_bytes: [u8; 32] = hello.try_into().map_err(|_| anyhow::anyhow!("bad hello"))?;
+    let public_ed = VerifyingKey::from_bytes(&public_ed_bytes).map_err(|_| anyhow::anyhow!("invalid public_ed"))?;
+
+    if ratelimit::is_banned(&pool, &public_ed_bytes, &ip).await {
+        wr.write_all(&text_frame("Banned")).await?;
+        anyhow::bail!("banned");
+    }
+
+    let row: Option<accounts::LoginRow> = sqlx::query_as(&accounts::LOGIN_SQL)
+        .bind(&public_ed_bytes[..])
+        .fetch_optional(&pool)
+        .await?;
+    let Some((id, public_x, status, status_reason)) = row else {
+        wr.write_all(&text_frame("Unknown device")).await?;
+        anyhow::bail!("unknown device");
+    };
+    if let Some(reason) = accounts::denied(status, status_reason) {
+        wr.write_all(&text_frame(&reason)).await?;
+        anyhow::bail!("{}", reason);
+    }
+    let public_x: [u8; 32] = public_x.try_into().unwrap_or([0u8; 32]);
+    tracing::Span::current().record("user_id", id);
+
+    // writer: всё, что для этого id (из hub и ответы), в сокет
+    let (tx, mut rx) = mpsc::unbounded_channel::<Outgoing>();
+    let (abort_handle, abort_reg) = AbortHandle::new_pair();
+    let reader_abort = abort_handle.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let bytes = match msg {
+                Outgoing::Binary(b) => frame(&b),
+                Outgoing::Text(t) => text_frame(&t),
+                Outgoing::Close => break,
+            };
+            if wr.write_all(&bytes).await.is_err() { break; }
+        }
+        let _ = wr.shutdown().await;
+        reader_abort.abort();
+    });
+
+    let token = hub_state.add(id, Conn::Channel(tx.clone()), abort_handle, ip.clone(), public_x, public_ed, Route::Device);
+    tracing::debug!("TCP connected: {} from {}", id, ip);
+
+    let reader = {
+        let hub_state = hub_state.clone();
+        let tx = tx.clone();
+        let mut limits = ConnLimits::new(ip.clone());
+        async move {
+            loop {
+                let bytes = match read_frame(&mut rd).await {
+                    Ok(Some(b)) => b,
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                        Conn::Channel(tx.clone()).close_with(actix_ws::CloseCode::Size, "frame too large").await;
+                        break;
+                    }
+                    _ => break,
+                };
+                hub_state.renew_heartbeat(id);
+                if bytes.is_empty() {
+                    continue; // keepalive
+                }
+                let Some(reply) = packet(id, &public_x, &public_ed, &bytes, &pool, &hub_state, &mut limits).await else {
+                    continue;
+                };
+                if matches!(reply, Outgoing::Close) {
+                    Conn::Channel(tx.clone()).close_with(actix_ws::CloseCode::Policy, &ratelimit::ban_reason()).await;
+                    break;
+                }
+                if tx.send(reply).is_err() {
+                    break;
+                }
+            }
+        }
+    };
+
+    let _ = Abortable::new(reader, abort_reg).await;
+    let _ = tx.send(Outgoing::Close);
+    hub_state.del(id, token);
+    tracing::debug!("TCP disconnected: {}", id);
+    Ok(())
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_roundtrip() {
+        let mut wire = frame(&[1, 2, 3]);
+        wire.extend_from_slice(&frame(&[]));
+        wire.extend_from_slice(&text_frame("timestamp_error:1"));
+        let mut r: &[u8] = &wire;
+
+        assert_eq!(read_frame(&mut r).await.unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_frame(&mut r).await.unwrap(), Some(vec![]));
+        let text = read_frame(&mut r).await.unwrap().unwrap();
+        assert_eq!(&text[..4], &TEXT_ADDR.to_le_bytes());
+        assert_eq!(&text[4..], b"timestamp_error:1");
+        assert_eq!(read_frame(&mut r).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_rejected() {
+        let wire = ((CONFIG.max_frame_size + 1) as u32).to_le_bytes();
+        let mut r: &[u8] = &wire;
+        assert!(read_frame(&mut r).await.is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// HTTPS/WSS прямо в сервере - для площадок без reverse proxy (public_ed в URL не должен ходить открытым).
+// Сертификат перечитывается по SIGHUP и при изменении файлов (проверка раз в tls_reload_sec).
+// Уже установленные соединения не трогаем: новый сертификат получают только новые handshake.
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use crate::config::CONFIG;
+
+#[derive(Debug)]
+struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load(cert: &str, key: &str) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("tls_cert {}: {:?}", cert, e))?;
+    if certs.is_empty() {
+        anyhow::bail!("tls_cert {}: no certificates", cert);
+    }
+    let private = PrivateKeyDer::from_pem_file(key).map_err(|e| anyhow::anyhow!("tls_key {}: {:?}", key, e))?;
+    let signing = ring::sign::any_supported_type(&private).map_err(|e| anyhow::anyhow!("tls_key {}: {}", key, e))?;
+    Ok(CertifiedKey::new(certs, signing))
+}
+
+fn mtimes() -> (Option<SystemTime>, Option<SystemTime>) {
+    let m = |p: &str| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    (m(&CONFIG.tls_cert), m(&CONFIG.tls_key))
+}
+
+impl CertResolver {
+    // битый или недописанный файл - оставляем старый сертификат
+    fn reload(&self) {
+        match load(&CONFIG.tls_cert, &CONFIG.tls_key) {
+            Ok(ck) => {
+                *self.current.write().unwrap() = Arc::new(ck);
+                tracing::info!("TLS: certificate reloaded");
+            }
+            Err(e) => tracing::error!("TLS: reload failed, keeping old certificate: {}", e),
+        }
+    }
+}
+
+pub fn server_config() -> anyhow::Result<rustls::ServerConfig> {
+    let resolver = Arc::new(CertResolver {
+        current: RwLock::new(Arc::new(load(&CONFIG.tls_cert, &CONFIG.tls_key)?)),
+    });
+    watch(resolver.clone());
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    Ok(config)
+}
+
+fn watch(resolver: Arc<CertResolver>) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("SIGHUP handler");
+        let poll = CONFIG.tls_reload_sec != 0;
+        let mut ticker = tokio::time::interval(Duration::from_secs(if poll { CONFIG.tls_reload_sec } else { 86400 }));
+        ticker.tick().await;
+        let mut seen = mtimes();
+
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = hup.recv() => tracing::info!("TLS: SIGHUP"),
+                _ = ticker.tick() => if !poll || mtimes() == seen { continue; },
+            }
+            #[cfg(not(unix))]
+            {
+                ticker.tick().await;
+                if !poll || mtimes() == seen { continue; }
+            }
+            if crate::shutdown::is_shutting_down() {
+                break;
+            }
+            seen = mtimes();
+            resolver.reload();
+        }
+    });
+}
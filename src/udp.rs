@@ -0,0 +1,251 @@
+// UDP для батарейных датчиков: проснулся, отправил одно измерение, уснул.
+//
+// Датаграмма: [0x01][public_ed 32][nonce|ciphertext|sig]
+//         или [0x02][id u32 LE][nonce|ciphertext|sig]
+//   внутри как обычно [msg_id u16 LE][cmd u8][body], принимается только cmd 0x10
+//   msg_id != 0 - прислать ответ: [0u32][encrypt_and_sign([msg_id][0x01][body])]
+//   msg_id == 0 - без ответа
+//   неверное время - ответ [0xFFFFFFFF]"timestamp_error:<unixtime>" (как в tcp.rs)
+// Повтор той же подписи в пределах окна nonce отбрасывается.
+
+use sqlx::PgPool;
+use tokio::net::UdpSocket;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use ed25519_dalek::VerifyingKey;
+use crate::accounts::ACTIVE_SQL;
+use crate::crypto25519::{self, DecryptError};
+use crate::hub::{HubState, UserId};
+use crate::ratelimit::{self, Kind, RateLimiter};
+use crate::server::{server, server_frame};
+use crate::{CONFIG, MY_CONFIG};
+
+const MAX_DATAGRAM: usize = 1500;
+
+#[derive(Debug, PartialEq)]
+pub enum Sender {
+    Ed([u8; 32]),
+    Id(UserId),
+}
+
+pub fn parse_datagram(buf: &[u8]) -> Option<(Sender, &[u8])> {
+    match *buf.first()? {
+        0x01 if buf.len() > 33 => Some((Sender::Ed(buf[1..33].try_into().ok()?), &buf[33..])),
+        0x02 if buf.len() > 5 => Some((Sender::Id(u32::from_le_bytes(buf[1..5].try_into().ok()?) as UserId), &buf[5..])),
+        _ => None,
+    }
+}
+
+// подписи, уже принятые от устройства, пока их nonce не вышел из окна
+// устройства, которые больше не пишут, чистятся внутри fresh не чаще раза в PRUNE_SEC
+#[derive(Default)]
+pub struct ReplayCache {
+    seen: HashMap<UserId, Vec<(u64, [u8; 64])>>,
+    last_prune: u64,
+}
+
+const PRUNE_SEC: u64 = 60;
+
+impl ReplayCache {
+    pub fn fresh(&mut self, id: UserId, nonce: u64, sig: [u8; 64], now: u64) -> bool {
+        if now.abs_diff(self.last_prune) > PRUNE_SEC {
+            self.prune(now);
+        }
+        let list = self.seen.entry(id).or_default();
+        list.retain(|(t, _)| now.abs_diff(*t) <= CONFIG.nonce_skew_sec);
+        if list.iter().any(|(_, s)| *s == sig) {
+            return false;
+        }
+        list.push((nonce, sig));
+        true
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.last_prune = now;
+        self.seen.retain(|_, list| {
+            list.retain(|(t, _)| now.abs_diff(*t) <= CONFIG.nonce_skew_sec);
+            !list.is_empty()
+        });
+    }
+}
+
+pub fn start(pool: PgPool, hub_state: Arc<HubState>, host: &str, port: u16) -> anyhow::Result<()> {
+    let addr = SocketAddr::new(host.parse()?, port);
+    tokio::spawn(async move {
+        let sock = match UdpSocket::bind(addr).await {
+            Ok(s) => Arc::new(s),
+            Err(e) => {
+                tracing::error!("UDP bind {} failed: {:?}", addr, e);
+                return;
+            }
+        };
+        tracing::info!("UDP: udp://{}", addr);
+
+        let mut limiter = RateLimiter::new(CONFIG.udp_rate_per_sec, CONFIG.udp_burst);
+        let replay = Arc::new(Mutex::new(ReplayCache::default()));
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            let (n, peer) = match sock.recv_from(&mut buf).await {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::warn!("UDP recv error: {:?}", e);
+                    continue;
+                }
+            };
+            if crate::shutdown::is_shutting_down() {
+                break;
+            }
+            if !limiter.check(peer.ip(), Instant::now()) {
+                tracing::debug!("UDP rate limit: {}", peer);
+                continue;
+            }
+
+            let data = buf[..n].to_vec();
+            let (sock, pool, hub_state, replay) = (sock.clone(), pool.clone(), hub_state.clone(), replay.clone());
+            tokio::spawn(async move {
+                if let Some(reply) = datagram(&data, peer, &pool, &hub_state, &replay).await
+                    && let Err(e) = sock.send_to(&reply, peer).await
+                {
+                    tracing::debug!("UDP send to {} failed: {:?}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn datagram(
+    buf: &[u8],
+    peer: SocketAddr,
+    pool: &PgPool,
+    hub_state: &Arc<HubState>,
+    replay: &Mutex<ReplayCache>,
+) -> Option<Vec<u8>> {
+    let Some((sender, blob)) = parse_datagram(buf) else {
+        tracing::debug!("UDP bad datagram from {}", peer);
+        return None;
+    };
+
+    let sql = format!("SELECT id, public_x, public_ed FROM users WHERE {} = $1 AND {}",
+        if matches!(sender, Sender::Ed(_)) { "public_ed" } else { "id" }, ACTIVE_SQL);
+    let row: Option<(i32, Vec<u8>, Vec<u8>)> = match &sender {
+        Sender::Ed(ed) => sqlx::query_as(&sql).bind(&ed[..]),
+        Sender::Id(id) => sqlx::query_as(&sql).bind(*id),
+    }
+    .fetch_optional(pool)
+    .await
+    .unwrap_or_else(|e| {
+        tracing::warn!("UDP: DB error: {:?}", e);
+        None
+    });
+    let Some((id, public_x, public_ed)) = row else {
+        tracing::debug!("UDP unknown sender {:?} from {}", sender, peer);
+        return None;
+    };
+    if ratelimit::is_banned(pool, &public_ed, &peer.ip().to_string()).await {
+        tracing::debug!("UDP banned {} from {}", id, peer);
+        return None;
+    }
+    let public_x: [u8; 32] = public_x.try_into().ok()?;
+    let public_ed = VerifyingKey::from_bytes(&public_ed.try_into().ok()?).ok()?;
+
+    let bin = match crypto25519::verify_and_decrypt(blob, &MY_CONFIG.secret_x, &public_x, &public_ed, CONFIG.nonce_skew_sec)
+        .inspect_err(crate::metrics::decrypt_failed)
+    {
+        Ok(v) if v.len() >= 3 => v,
+        Err(DecryptError::BadNonce) => {
+            tracing::debug!("UDP bad nonce from {}", id);
+            let mut out = crate::tcp::TEXT_ADDR.to_le_bytes().to_vec();
+            out.extend_from_slice(format!("timestamp_error:{}", crypto25519::get_unixtime()).as_bytes());
+            return Some(out);
+        }
+        _ => {
+            tracing::debug!("UDP decrypt failed from {}", id);
+            return None;
+        }
+    };
+
+    // подпись уже проверена: [nonce 8]...[sig 64]
+    let nonce = u64::from_le_bytes(blob[0..8].try_into().ok()?);
+    let sig: [u8; 64] = blob[blob.len() - 64..].try_into().ok()?;
+    if !replay.lock().unwrap().fresh(id, nonce, sig, crypto25519::get_unixtime()) {
+        tracing::warn!("UDP replay from {} ({})", id, peer);
+        return None;
+    }
+
+    let message_id = u16::from_le_bytes([bin[0], bin[1]]);
+    let cmd = bin[2];
+    // limiter выше - по IP; аккаунт общий с WS / TCP / HTTP / MQTT, смена IP его не обходит
+    // после проверки подписи: чужими датаграммами не выбрать лимит настоящего устройства
+    let body = if cmd == 0x10 && !ratelimit::account_check(id, Kind::Telemetry) {
+        crate::metrics::inc(&crate::metrics::RATE_LIMITED);
+        tracing::debug!("UDP rate limited: {}", id);
+        serde_json::to_vec(&ratelimit::limited_json(Kind::Telemetry)).unwrap()
+    } else if cmd == 0x10 {
+        server(cmd, id, &bin[3..], pool, hub_state).await
+    } else {
+        serde_json::to_vec(&serde_json::json!({ "error": "Only 0x10 over UDP" })).unwrap()
+    };
+
+    if let Err(e) = sqlx::query(
+        r#"INSERT INTO presence (user_id, last_seen, last_ip) VALUES ($1, now(), $2)
+           ON CONFLICT (user_id) DO UPDATE SET last_seen = now(), last_ip = EXCLUDED.last_ip"#)
+        .bind(id).bind(peer.ip().to_string()).execute(pool).await
+    {
+        tracing::warn!("UDP: presence DB error for {}: {:?}", id, e);
+    }
+
+    if message_id == 0 {
+        return None;
+    }
+    Some(server_frame(message_id, 0x01, &body, &public_x))
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sender() {
+        let mut d = vec![0x01];
+        d.extend_from_slice(&[7u8; 32]);
+        d.extend_from_slice(b"blob");
+        assert_eq!(parse_datagram(&d), Some((Sender::Ed([7u8; 32]), &b"blob"[..])));
+
+        let mut d = vec![0x02];
+        d.extend_from_slice(&42u32.to_le_bytes());
+        d.extend_from_slice(b"blob");
+        assert_eq!(parse_datagram(&d), Some((Sender::Id(42), &b"blob"[..])));
+
+        assert_eq!(parse_datagram(&[0x02, 1, 0, 0, 0]), None);
+        assert_eq!(parse_datagram(&[0x03, 1, 2, 3, 4, 5]), None);
+    }
+
+    #[test]
+    fn replay_rejected_within_window() {
+        let mut c = ReplayCache::default();
+        assert!(c.fresh(1, 1000, [1u8; 64], 1000));
+        assert!(!c.fresh(1, 1000, [1u8; 64], 1002));
+        assert!(c.fresh(1, 1000, [2u8; 64], 1002));
+        assert!(c.fresh(2, 1000, [1u8; 64], 1002)); // другое устройство
+    }
+
+    #[test]
+    fn replay_cache_prunes_silent_devices() {
+        let mut c = ReplayCache::default();
+        let t = CONFIG.nonce_skew_sec + PRUNE_SEC + 1000;
+        assert!(c.fresh(1, t, [1u8; 64], t));
+        assert!(c.fresh(2, t, [1u8; 64], t));
+        // устройство 1 замолчало, 2 пишет дальше - запись 1 уходит при следующем prune
+        let later = t + CONFIG.nonce_skew_sec + PRUNE_SEC + 1;
+        assert!(c.fresh(2, later, [2u8; 64], later));
+        assert_eq!(c.seen.len(), 1);
+        assert!(c.seen.contains_key(&2));
+    }
+}
@@ -0,0 +1,332 @@
+use hmac::{Hmac, Mac};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use sqlx::PgPool;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use crate::config::CONFIG;
+use crate::hub::UserId;
+
+// SSRF: имя резолвится при каждой доставке и только в публичные адреса, редиректы не идем
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .user_agent(concat!("aguardia/", env!("CARGO_PKG_VERSION")))
+        .dns_resolver(Arc::new(PublicOnly))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("webhook http client")
+});
+
+// наружу можно: не loopback, не частные сети, не link-local (169.254.169.254 - metadata облаков), не ULA
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+                || v4.is_broadcast() || v4.is_multicast() || v4.is_documentation()
+                || a == 0 || (a == 100 && (b & 0xc0) == 64)) // 0/8, CGNAT 100.64/10
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00  // fc00::/7 unique local
+                || (first & 0xffc0) == 0xfe80) // fe80::/10 link-local
+        }
+    }
+}
+
+// резолвер для CLIENT: хоть один адрес не публичный - отказ (DNS rebinding тоже сюда)
+struct PublicOnly;
+
+impl reqwest::dns::Resolve for PublicOnly {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Some(bad) = addrs.iter().find(|a| !is_public(a.ip())) {
+                return Err(format!("{} resolves to non-public address {}", host, bad.ip()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// X-Aguardia-Signature: sha256=<hex HMAC-SHA256(secret, body)>
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// только https и не во внутреннюю сеть: IP в url проверяем здесь, имена - резолвер при доставке
+pub fn check_url(s: &str) -> Result<(), String> {
+    let url = url::Url::parse(s).map_err(|_| "bad url".to_string())?;
+    if url.scheme() != "https" {
+        return Err("url must be https".into());
+    }
+    let ip = match url.host() {
+        Some(url::Host::Domain(d)) if d == "localhost" || d.ends_with(".localhost") => return Err("url must not be local".into()),
+        Some(url::Host::Domain(_)) => return Ok(()),
+        Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+        None => return Err("bad url".into()),
+    };
+    if !is_public(ip) {
+        return Err("url must not point to a private address".into());
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Delivery {
+    pub ok: bool,
+    pub attempts: u32,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+// POST с повторами: base_delay, 2*base_delay, 4*base_delay...
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    event: &str,
+    body: &[u8],
+    retries: u32,
+    base_delay: Duration,
+) -> Delivery {
+    let signature = sign(secret, body);
+    let mut out = Delivery { ok: false, attempts: 0, status_code: None, error: None };
+
+    loop {
+        out.attempts += 1;
+        let result = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Aguardia-Event", event)
+            .header("X-Aguardia-Signature", &signature)
+            .body(body.to_vec())
+            .send()
+            .await;
+
+        match result {
+            Ok(resp) if resp.status().is_success() => {
+                out.ok = true;
+                out.status_code = Some(resp.status().as_u16());
+                out.error = None;
+                return out;
+            }
+            Ok(resp) => {
+                out.status_code = Some(resp.status().as_u16());
+                out.error = Some(format!("HTTP {}", resp.status()));
+            }
+            Err(e) => {
+                out.status_code = None;
+                out.error = Some(e.to_string());
+            }
+        }
+
+        if out.attempts > retries {
+            return out;
+        }
+        tokio::time::sleep(base_delay * 2u32.saturating_pow(out.attempts - 1)).await;
+    }
+}
+
+// разослать событие по всем webhook'ам устройства
+pub async fn dispatch(pool: &PgPool, device_id: UserId, event: &str, data: Value) {
+    let hooks = sqlx::query_as::<_, (i32, String, String)>(
+        "SELECT id, url, secret FROM webhooks WHERE device_id = $1 AND active")
+        .bind(device_id)
+        .fetch_all(pool)
+        .await;
+    let hooks = match hooks {
+        Ok(h) => h,
+        Err(e) => {
+            tracing::warn!("webhooks: DB error for device {}: {:?}", device_id, e);
+            return;
+        }
+    };
+    if hooks.is_empty() {
+        return;
+    }
+
+    let payload = json!({
+        "event": event,
+        "device_id": device_id,
+        "time": crate::crypto25519::get_unixtime(),
+        "data": data,
+    });
+    let body = serde_json::to_vec(&payload).unwrap();
+
+    for (webhook_id, url, secret) in hooks {
+        let pool = pool.clone();
+        let event = event.to_string();
+        let body = body.clone();
+        let payload = payload.clone();
+        crate::shutdown::spawn(async move {
+            // url мог быть добавлен до проверок - еще раз здесь
+            let d = match check_url(&url) {
+                Ok(()) => deliver(
+                    &CLIENT, &url, &secret, &event, &body,
+                    CONFIG.webhook_retries,
+                    Duration::from_secs(CONFIG.webhook_retry_base_sec),
+                ).await,
+                Err(e) => Delivery { ok: false, attempts: 0, status_code: None, error: Some(e) },
+            };
+            if !d.ok {
+                tracing::warn!("webhook {} failed after {} attempts: {:?}", webhook_id, d.attempts, d.error);
+            }
+            if let Err(e) = sqlx::query(
+                r#"INSERT INTO webhook_deliveries (webhook_id, event, payload, attempts, ok, status_code, error)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7)"#)
+                .bind(webhook_id).bind(&event).bind(&payload)
+                .bind(d.attempts as i32).bind(d.ok).bind(d.status_code.map(|c| c as i32)).bind(&d.error)
+                .execute(&pool).await
+            {
+                tracing::warn!("webhooks: DB error logging delivery {}: {:?}", webhook_id, e);
+            }
+        });
+    }
+}
+
+// retention: раз в час удалить журнал доставок старше webhook_log_days (0 = хранить всё)
+pub fn start(pool: PgPool) {
+    if CONFIG.webhook_log_days == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            ticker.tick().await;
+            if crate::shutdown::is_shutting_down() {
+                break;
+            }
+            let _in_flight = crate::shutdown::in_flight();
+            match sqlx::query("DELETE FROM webhook_deliveries WHERE time < now() - make_interval(days => $1)")
+                .bind(CONFIG.webhook_log_days as i32)
+                .execute(&pool).await
+            {
+                Ok(r) if r.rows_affected() > 0 => tracing::info!("webhooks: {} old deliveries removed", r.rows_affected()),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("webhooks: retention failed: {:?}", e),
+            }
+        }
+    });
+}
+
+// ==================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // локальная заглушка: отвечает по очереди кодами из statuses, запросы складывает в requests
+    async fn stub(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                // заголовки + тело по Content-Length
+                loop {
+                    let n = sock.read(&mut chunk).await.unwrap();
+                    if n == 0 { break; }
+                    buf.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&buf).to_string();
+                    if let Some(head_end) = text.find("\r\n\r\n") {
+                        let len = text.lines()
+                            .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if buf.len() >= head_end + 4 + len { break; }
+                    }
+                }
+                seen.lock().unwrap().push(String::from_utf8_lossy(&buf).to_string());
+                let resp = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                sock.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn sign_known_vector() {
+        // RFC 4231 test case 2
+        let mac = sign("Jefe", b"what do ya want for nothing?");
+        assert_eq!(mac, "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn only_https_urls() {
+        assert!(check_url("https://ha.example.com/api/webhook/x").is_ok());
+        assert!(check_url("https://93.184.216.34/hook").is_ok());
+        assert!(check_url("http://localhost:8123/hook").is_err());
+        assert!(check_url("https://localhost:8123/hook").is_err());
+        assert!(check_url("http://ha.example.com/hook").is_err());
+        assert!(check_url("ftp://example.com").is_err());
+        assert!(check_url("nonsense").is_err());
+        for private in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+                        "[::1]", "[fd00::1]", "[fe80::1]", "[::ffff:127.0.0.1]"] {
+            assert!(check_url(&format!("https://{}/hook", private)).is_err(), "{}", private);
+        }
+    }
+
+    // имя, которое резолвится во внутреннюю сеть, не доходит до заглушки
+    #[tokio::test]
+    async fn resolver_rejects_private() {
+        let (url, requests) = stub(vec![200]).await;
+        let url = url.replace("127.0.0.1", "localhost");
+
+        let d = deliver(&CLIENT, &url, "k", "data", b"{}", 0, Duration::from_millis(10)).await;
+
+        assert!(!d.ok);
+        assert_eq!(d.status_code, None);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deliver_retries_then_succeeds() {
+        let (url, requests) = stub(vec![500, 503, 200]).await;
+        let body = br#"{"event":"data","device_id":7}"#;
+        let client = reqwest::Client::new();
+
+        let d = deliver(&client, &url, "s3cret", "data", body, 5, Duration::from_millis(10)).await;
+
+        assert!(d.ok);
+        assert_eq!(d.attempts, 3);
+        assert_eq!(d.status_code, Some(200));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let last = &requests[2];
+        assert!(last.starts_with("POST /hook HTTP/1.1"));
+        assert!(last.to_ascii_lowercase().contains(&format!("x-aguardia-signature: {}", sign("s3cret", body))));
+        assert!(last.to_ascii_lowercase().contains("x-aguardia-event: data"));
+        assert!(last.ends_with(std::str::from_utf8(body).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn deliver_gives_up() {
+        let (url, requests) = stub(vec![500, 500]).await;
+        let client = reqwest::Client::new();
+
+        let d = deliver(&client, &url, "k", "alert", b"{}", 1, Duration::from_millis(10)).await;
+
+        assert!(!d.ok);
+        assert_eq!(d.attempts, 2);
+        assert_eq!(d.status_code, Some(500));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}